@@ -1 +1,2 @@
-mod mssmt;
+pub mod mssmt;
+pub mod primitives;
@@ -0,0 +1,218 @@
+//! An async version of [TreeStore], for services running on tokio. Storage calls may take a
+//! while, and an async store lets the executor run other tasks in the meantime, instead of
+//! blocking one of its threads.
+//!
+//! Any [TreeStore] can be used as an async one with [BlockingStore], that runs each call on
+//! tokio's blocking pool. [AsyncMSSMTree](super::async_tree::AsyncMSSMTree) is a tree on top
+//! of those stores.
+use std::{future::Future, sync::Arc};
+
+use tokio::task::{spawn_blocking, JoinError};
+
+use super::{
+    node::{DiskBranchNode, LeafNode},
+    node_hash::NodeHash,
+    tree_backend::{Op, TreeStore},
+};
+
+/// Same as [TreeStore], but every call returns a future. This only has what trees need, and
+/// each call behaves like the [TreeStore] method of the same name.
+pub trait AsyncTreeStore {
+    type Error;
+    /// Applies all `ops` inside `namespace` as a single atomic write, see
+    /// [TreeStore::apply_batch]
+    fn apply_batch(
+        &self,
+        namespace: &str,
+        ops: Vec<Op>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns the root saved for the tree in `namespace`, if any
+    fn get_root(
+        &self,
+        namespace: &str,
+    ) -> impl Future<Output = Result<Option<NodeHash>, Self::Error>> + Send;
+    /// Fetches a single branch node from storage
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> impl Future<Output = Result<Option<DiskBranchNode>, Self::Error>> + Send;
+    /// Fetches a leaf node from storage
+    fn fetch_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> impl Future<Output = Result<Option<LeafNode>, Self::Error>> + Send;
+}
+
+/// Lets async trees borrow their store as well. The returned futures hold `&T`, so `T` must
+/// be `Sync` for them to be `Send`.
+impl<T: AsyncTreeStore + Sync> AsyncTreeStore for &T {
+    type Error = T::Error;
+
+    fn apply_batch(
+        &self,
+        namespace: &str,
+        ops: Vec<Op>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).apply_batch(namespace, ops)
+    }
+    fn get_root(
+        &self,
+        namespace: &str,
+    ) -> impl Future<Output = Result<Option<NodeHash>, Self::Error>> + Send {
+        (**self).get_root(namespace)
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> impl Future<Output = Result<Option<DiskBranchNode>, Self::Error>> + Send {
+        (**self).fetch_branch(namespace, hash)
+    }
+    fn fetch_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> impl Future<Output = Result<Option<LeafNode>, Self::Error>> + Send {
+        (**self).fetch_leaf(namespace, hash)
+    }
+}
+
+/// Makes a synchronous [TreeStore] async, by running every call on tokio's blocking pool.
+/// This must be used from inside a tokio runtime. Clones share the same store.
+#[derive(Debug)]
+pub struct BlockingStore<Persistence> {
+    inner: Arc<Persistence>,
+}
+
+impl<Persistence> Clone for BlockingStore<Persistence> {
+    fn clone(&self) -> Self {
+        BlockingStore {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<Persistence> BlockingStore<Persistence>
+where
+    Persistence: TreeStore + Send + Sync + 'static,
+    Persistence::Error: Send + 'static,
+{
+    pub fn new(inner: Persistence) -> BlockingStore<Persistence> {
+        BlockingStore {
+            inner: Arc::new(inner),
+        }
+    }
+    /// The synchronous store our calls are sent to. Calling it directly blocks the current
+    /// thread.
+    pub fn inner(&self) -> &Persistence {
+        &self.inner
+    }
+    /// Runs `call` on the blocking pool, with our store and an owned copy of `namespace`
+    async fn run<T: Send + 'static>(
+        &self,
+        namespace: &str,
+        call: impl FnOnce(&Persistence, &str) -> Result<T, Persistence::Error> + Send + 'static,
+    ) -> Result<T, BlockingStoreError<Persistence::Error>> {
+        let inner = self.inner.clone();
+        let namespace = namespace.to_owned();
+        spawn_blocking(move || call(&inner, &namespace))
+            .await?
+            .map_err(BlockingStoreError::Store)
+    }
+}
+
+impl<Persistence> AsyncTreeStore for BlockingStore<Persistence>
+where
+    Persistence: TreeStore + Send + Sync + 'static,
+    Persistence::Error: Send + 'static,
+{
+    type Error = BlockingStoreError<Persistence::Error>;
+
+    async fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        self.run(namespace, move |store, namespace| {
+            store.apply_batch(namespace, ops)
+        })
+        .await
+    }
+    async fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        self.run(namespace, |store, namespace| store.get_root(namespace))
+            .await
+    }
+    async fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        self.run(namespace, move |store, namespace| {
+            store.fetch_branch(namespace, hash)
+        })
+        .await
+    }
+    async fn fetch_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<LeafNode>, Self::Error> {
+        self.run(namespace, move |store, namespace| {
+            store.fetch_leaf(namespace, hash)
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockingStoreError<StoreError> {
+    /// The inner store returned an error
+    Store(StoreError),
+    /// The blocking task panicked, or the runtime is shutting down
+    Join(JoinError),
+}
+impl<StoreError> From<JoinError> for BlockingStoreError<StoreError> {
+    fn from(value: JoinError) -> Self {
+        Self::Join(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        memory_db::MemoryDatabase,
+        node::{LeafNode, MSSMTNode},
+        tree_backend::{Op, DEFAULT_NAMESPACE},
+    };
+
+    use super::{AsyncTreeStore, BlockingStore};
+
+    #[test]
+    fn test_blocking_store() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let store = BlockingStore::new(MemoryDatabase::new());
+        let leaf = LeafNode::new(vec![1], 1);
+        runtime.block_on(async {
+            store
+                .apply_batch(
+                    DEFAULT_NAMESPACE,
+                    vec![Op::InsertLeaf(leaf.clone()), Op::SetRoot(leaf.node_hash())],
+                )
+                .await
+                .unwrap();
+            let found = store
+                .fetch_leaf(DEFAULT_NAMESPACE, leaf.node_hash())
+                .await
+                .unwrap();
+            assert_eq!(found, Some(leaf.clone()));
+            let root = store.get_root(DEFAULT_NAMESPACE).await.unwrap();
+            assert_eq!(root, Some(leaf.node_hash()));
+            assert!(store
+                .fetch_branch(DEFAULT_NAMESPACE, leaf.node_hash())
+                .await
+                .unwrap()
+                .is_none());
+        });
+        assert_eq!(store.inner().len().unwrap(), 1);
+    }
+}
@@ -0,0 +1,333 @@
+//! An async version of [MSSMTree](super::tree::MSSMTree), on top of an [AsyncTreeStore]. Every
+//! operation works exactly like it does in the sync tree, and builds the same nodes, so both
+//! trees can be used on the same storage and produce the same roots and proofs. Only the
+//! storage calls are different here, everything else is shared with the sync tree.
+use super::{
+    async_backend::AsyncTreeStore,
+    error::Error,
+    node::{ComputedNode, DiskBranchNode, LeafNode, MSSMTNode, Node},
+    node_hash::NodeHash,
+    proof::Proof,
+    tree::{empty_hashes_with_depth, empty_tree_with_depth, hash_path, next_step, BatchChanges},
+    tree_backend::{Op, DEFAULT_NAMESPACE},
+};
+
+/// A Merkle Sum Sparse Merkle Tree whose storage is async, see
+/// [MSSMTree](super::tree::MSSMTree) for how the tree works, and what `DEPTH` means.
+pub struct AsyncMSSMTree<Persistence: AsyncTreeStore, const DEPTH: usize = 256> {
+    /// A backend for our tree. We store nodes in key-value pairs.
+    database: Persistence,
+    /// Points to this tree's root
+    root: NodeHash,
+    /// The pre-computed values for an empty tree
+    empty_tree: &'static [Node],
+    /// The hashes of `empty_tree`, to check if a subtree is empty
+    empty_hashes: &'static [NodeHash],
+    /// Where our nodes live inside the storage
+    namespace: String,
+    /// If this tree was opened by name, we save our root in our namespace after each change
+    save_root: bool,
+}
+
+impl<Persistence: AsyncTreeStore> AsyncMSSMTree<Persistence> {
+    /// Creates an empty tree in the [DEFAULT_NAMESPACE]
+    pub fn new(database: Persistence) -> AsyncMSSMTree<Persistence> {
+        AsyncMSSMTree::with_namespace(database, DEFAULT_NAMESPACE)
+    }
+    /// Creates an empty tree that keeps its nodes in `namespace`
+    pub fn with_namespace(database: Persistence, namespace: &str) -> AsyncMSSMTree<Persistence> {
+        AsyncMSSMTree::with_depth(database, namespace)
+    }
+    /// Opens the tree saved as `name`, or an empty one if there's no such tree yet, see
+    /// [MSSMTree::open](super::tree::MSSMTree::open)
+    pub async fn open(
+        database: Persistence,
+        name: &str,
+    ) -> Result<AsyncMSSMTree<Persistence>, Error<Persistence::Error>> {
+        AsyncMSSMTree::open_with_depth(database, name).await
+    }
+}
+
+impl<Persistence: AsyncTreeStore, const DEPTH: usize> AsyncMSSMTree<Persistence, DEPTH> {
+    /// Creates an empty tree with `DEPTH` levels, that keeps its nodes in `namespace`, see
+    /// [MSSMTree::with_depth](super::tree::MSSMTree::with_depth)
+    pub fn with_depth(database: Persistence, namespace: &str) -> AsyncMSSMTree<Persistence, DEPTH> {
+        let empty_hashes = empty_hashes_with_depth::<DEPTH>();
+        AsyncMSSMTree {
+            database,
+            root: empty_hashes[0],
+            empty_tree: empty_tree_with_depth::<DEPTH>(),
+            empty_hashes,
+            namespace: namespace.to_owned(),
+            save_root: false,
+        }
+    }
+    /// Like [AsyncMSSMTree::open], for a tree with `DEPTH` levels
+    pub async fn open_with_depth(
+        database: Persistence,
+        name: &str,
+    ) -> Result<AsyncMSSMTree<Persistence, DEPTH>, Error<Persistence::Error>> {
+        let mut tree = AsyncMSSMTree::with_depth(database, name);
+        if let Some(root) = tree.database.get_root(name).await.map_err(Error::Storage)? {
+            tree.root = root;
+        }
+        tree.save_root = true;
+        Ok(tree)
+    }
+    /// Returns the hash of this tree's root
+    pub fn root_hash(&self) -> NodeHash {
+        self.root
+    }
+    /// Returns this tree's root node, that also holds the sum of all leaves
+    pub async fn root(&self) -> Result<Node, Error<Persistence::Error>> {
+        self.fetch_node(self.root, 0).await
+    }
+    /// Returns the storage backing this tree
+    pub fn database(&self) -> &Persistence {
+        &self.database
+    }
+    async fn fetch_branch(
+        &self,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Error<Persistence::Error>> {
+        self.database
+            .fetch_branch(&self.namespace, hash)
+            .await
+            .map_err(Error::Storage)
+    }
+    async fn fetch_leaf(
+        &self,
+        hash: NodeHash,
+    ) -> Result<Option<LeafNode>, Error<Persistence::Error>> {
+        self.database
+            .fetch_leaf(&self.namespace, hash)
+            .await
+            .map_err(Error::Storage)
+    }
+    /// Returns the children of `hash`, a branch at depth `idx`. Empty subtrees aren't
+    /// stored, so their children come straight from the empty tree.
+    async fn children(
+        &self,
+        hash: NodeHash,
+        idx: usize,
+    ) -> Result<(NodeHash, NodeHash), Error<Persistence::Error>> {
+        if hash == self.empty_hashes[idx] {
+            let hash = self.empty_hashes[idx + 1];
+            return Ok((hash, hash));
+        }
+        match self.fetch_branch(hash).await? {
+            Some(node) => Ok((*node.l_child(), *node.r_child())),
+            None => Err(Error::MissingNode(hash)),
+        }
+    }
+    /// Returns the node at `hash`, that sits at depth `idx`, without asking the storage for
+    /// empty subtrees. Fails with [Error::MissingNode] if a non-empty node isn't stored.
+    async fn fetch_node(
+        &self,
+        hash: NodeHash,
+        idx: usize,
+    ) -> Result<Node, Error<Persistence::Error>> {
+        if hash == self.empty_hashes[idx] {
+            return Ok(self.empty_tree[idx].clone());
+        }
+        let node = if idx == DEPTH {
+            self.fetch_leaf(hash).await?.map(Node::Leaf)
+        } else {
+            self.fetch_branch(hash).await?.map(Node::Branch)
+        };
+        node.ok_or(Error::MissingNode(hash))
+    }
+    /// Inserts a new leaf into the tree
+    pub async fn insert(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
+        let leaf = LeafNode::new(data, sum);
+
+        let mut node = self.root;
+        let mut parents = Vec::with_capacity(DEPTH);
+        let mut siblings = Vec::with_capacity(DEPTH);
+        // Walks down the tree and grabs all parents and siblings on the way down
+        for idx in 0..DEPTH {
+            let (next, sibling) = next_step(&key, idx, self.children(node, idx).await?);
+            let sibling_sum = self.fetch_node(sibling, idx + 1).await?.node_sum();
+            parents.push(node);
+            siblings.push(Node::Computed(ComputedNode::new(sibling, sibling_sum)));
+            node = next;
+        }
+        let new_path = hash_path(&key, &leaf, &siblings)?;
+        let new_root = new_path[0].node_hash();
+
+        let changes = BatchChanges::for_path(self.empty_hashes, parents, node, new_path, leaf);
+        let mut ops = changes.into_ops(false);
+        if self.save_root {
+            ops.push(Op::SetRoot(new_root));
+        }
+        self.database
+            .apply_batch(&self.namespace, ops)
+            .await
+            .map_err(Error::Storage)?;
+        self.root = new_root;
+        Ok(())
+    }
+    /// Removes a node from the tree, indexed by a [NodeHash]
+    pub async fn delete(&mut self, key: NodeHash) -> Result<(), Error<Persistence::Error>> {
+        self.insert(key, vec![], 0).await
+    }
+    /// Updates a node that already exists
+    pub async fn update(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
+        self.insert(key, data, sum).await
+    }
+    /// Looks up a node and returns it's value
+    pub async fn lookup(
+        &self,
+        key: NodeHash,
+    ) -> Result<Option<LeafNode>, Error<Persistence::Error>> {
+        let mut node = self.root;
+        for idx in 0..DEPTH {
+            (node, _) = next_step(&key, idx, self.children(node, idx).await?);
+        }
+        if node == self.empty_hashes[DEPTH] {
+            return Ok(None);
+        }
+        self.fetch_leaf(node).await
+    }
+    /// Creates a proof of inclusion for `key`, or of it's absence if the key isn't in the
+    /// tree
+    pub async fn prove(&self, key: NodeHash) -> Result<Proof<DEPTH>, Error<Persistence::Error>> {
+        let mut proof = Vec::with_capacity(DEPTH);
+        let mut node = self.root;
+        for idx in 0..DEPTH {
+            let (next, sibling) = next_step(&key, idx, self.children(node, idx).await?);
+            node = next;
+            proof.push(self.fetch_node(sibling, idx + 1).await?);
+        }
+        Ok(Proof::with_depth(proof))
+    }
+    /// Creates a proof that `key` isn't in the tree, or `None` if it is
+    pub async fn prove_absence(
+        &self,
+        key: NodeHash,
+    ) -> Result<Option<Proof<DEPTH>>, Error<Persistence::Error>> {
+        if self.lookup(key).await?.is_some() {
+            return Ok(None);
+        }
+        self.prove(key).await.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        async_backend::BlockingStore,
+        error::Error,
+        memory_db::{AsyncMemoryDatabase, MemoryDatabase},
+        node::{LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        proof::{Proof, Provable, Verifiable},
+        tree::{MSSMTree, Tree},
+    };
+
+    use super::AsyncMSSMTree;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_same_as_sync() {
+        let mut tree = MSSMTree::new(MemoryDatabase::new());
+        let mut async_tree = AsyncMSSMTree::new(AsyncMemoryDatabase::new());
+        block_on(async {
+            for i in 0..10_u8 {
+                let key = NodeHash::from([i; 32]);
+                tree.insert(key, vec![i], i as u64).unwrap();
+                async_tree.insert(key, vec![i], i as u64).await.unwrap();
+            }
+            tree.delete(NodeHash::from([3; 32])).unwrap();
+            async_tree.delete(NodeHash::from([3; 32])).await.unwrap();
+            tree.update(NodeHash::from([5; 32]), vec![50], 50).unwrap();
+            async_tree
+                .update(NodeHash::from([5; 32]), vec![50], 50)
+                .await
+                .unwrap();
+            assert_eq!(async_tree.root_hash(), tree.root_hash());
+            assert_eq!(async_tree.root().await.unwrap().node_sum(), 87);
+
+            for i in 0..10_u8 {
+                let key = NodeHash::from([i; 32]);
+                let leaf = async_tree.lookup(key).await.unwrap();
+                assert_eq!(leaf, tree.lookup(key).unwrap());
+                let proof = async_tree.prove(key).await.unwrap();
+                let root = async_tree.root().await.unwrap();
+                let hashes = |proof: &Proof| -> Vec<_> {
+                    proof.nodes().iter().map(|node| node.node_hash()).collect()
+                };
+                assert_eq!(hashes(&proof), hashes(&tree.prove(key).unwrap()));
+                let leaf = leaf.unwrap_or_default();
+                assert!(proof.verify_root(&leaf, &key, &root).unwrap());
+            }
+            assert!(async_tree
+                .prove_absence(NodeHash::from([3; 32]))
+                .await
+                .unwrap()
+                .is_some());
+            assert!(async_tree
+                .prove_absence(NodeHash::from([4; 32]))
+                .await
+                .unwrap()
+                .is_none());
+
+            // Shallow trees too
+            let mut tree = MSSMTree::<_, 8>::with_depth(MemoryDatabase::new(), "shallow");
+            let mut async_tree =
+                AsyncMSSMTree::<_, 8>::with_depth(AsyncMemoryDatabase::new(), "shallow");
+            for i in 0..10_u8 {
+                let key = NodeHash::from([i; 32]);
+                tree.insert(key, vec![i], i as u64).unwrap();
+                async_tree.insert(key, vec![i], i as u64).await.unwrap();
+            }
+            assert_eq!(async_tree.root_hash(), tree.root_hash());
+            let proof = async_tree.prove(NodeHash::from([1; 32])).await.unwrap();
+            assert_eq!(proof.nodes().len(), 8);
+        });
+    }
+
+    #[test]
+    fn test_blocking_store() {
+        let store = BlockingStore::new(MemoryDatabase::new());
+        let writer = store.clone();
+        block_on(async {
+            // Trees can be moved into their own tasks
+            let task = tokio::spawn(async move {
+                let mut tree = AsyncMSSMTree::open(writer, "assets").await.unwrap();
+                tree.insert(NodeHash::from([0; 32]), vec![0], 10)
+                    .await
+                    .unwrap();
+                tree
+            });
+            let mut tree = task.await.unwrap();
+            let res = tree
+                .insert(NodeHash::from([1; 32]), vec![1], u64::MAX)
+                .await;
+            assert!(matches!(res, Err(Error::SumOverflow)));
+
+            let tree = AsyncMSSMTree::open(store.clone(), "assets").await.unwrap();
+            let leaf = tree.lookup(NodeHash::from([0; 32])).await.unwrap();
+            assert_eq!(leaf, Some(LeafNode::new(vec![0], 10)));
+        });
+        // Nodes written through the async tree are readable by the sync one
+        let tree = MSSMTree::open(store.inner(), "assets").unwrap();
+        assert_eq!(tree.root().unwrap().node_sum(), 10);
+    }
+}
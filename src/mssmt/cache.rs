@@ -0,0 +1,338 @@
+//! Every operation on a tree walks from the root down to a leaf, so the nodes near the root
+//! are fetched over and over. [CachedStore] wraps another store and keeps the most recently
+//! used branches and leaves in memory, so slow backends, like the ones on disk, are only hit
+//! for nodes we haven't seen lately.
+//!
+//! Writes go straight to the inner store, and the cache is only updated after they succeed.
+//! The cache can't see writes that bypass it, so once a store is wrapped, every write should
+//! go through the wrapper.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use super::{
+    node::{CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode, Node},
+    node_hash::NodeHash,
+    tree_backend::{Op, TreeStore},
+};
+
+/// A least recently used cache of nodes, keyed by namespace and hash
+#[derive(Debug)]
+struct Lru {
+    /// How many nodes we may hold
+    capacity: usize,
+    /// Increases every time a node is used, so older entries have smaller ticks
+    tick: u64,
+    /// Cached nodes, by namespace, with the tick they were last used at
+    entries: HashMap<String, HashMap<NodeHash, (Node, u64)>>,
+    /// Which node was used at each tick. The first entry is the next one to go.
+    order: BTreeMap<u64, (String, NodeHash)>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+    /// Returns a copy of the node at `hash`, marking it as the most recently used
+    fn get(&mut self, namespace: &str, hash: &NodeHash) -> Option<Node> {
+        let (node, last_used) = self.entries.get_mut(namespace)?.get_mut(hash)?;
+        let key = self
+            .order
+            .remove(last_used)
+            .expect("Every entry has it's place in the order");
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(node.clone())
+    }
+    /// Adds or replaces a node, evicting the least recently used one if we are full
+    fn put(&mut self, namespace: &str, hash: NodeHash, node: Node) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(namespace, &hash);
+        if self.len() >= self.capacity {
+            if let Some((_, (namespace, hash))) = self.order.pop_first() {
+                self.remove_entry(&namespace, &hash);
+            }
+        }
+        self.tick += 1;
+        self.entries
+            .entry(namespace.to_owned())
+            .or_default()
+            .insert(hash, (node, self.tick));
+        self.order.insert(self.tick, (namespace.to_owned(), hash));
+    }
+    fn remove(&mut self, namespace: &str, hash: &NodeHash) {
+        if let Some(last_used) = self.remove_entry(namespace, hash) {
+            self.order.remove(&last_used);
+        }
+    }
+    /// Removes a node from the entries, but not from the order. Returns when it was used.
+    fn remove_entry(&mut self, namespace: &str, hash: &NodeHash) -> Option<u64> {
+        let nodes = self.entries.get_mut(namespace)?;
+        let (_, last_used) = nodes.remove(hash)?;
+        if nodes.is_empty() {
+            self.entries.remove(namespace);
+        }
+        Some(last_used)
+    }
+}
+
+#[derive(Debug)]
+pub struct CachedStore<Persistence: TreeStore> {
+    inner: Persistence,
+    cache: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<Persistence: TreeStore> CachedStore<Persistence> {
+    /// Wraps `inner`, keeping at most `capacity` branches and leaves in memory
+    pub fn new(inner: Persistence, capacity: usize) -> CachedStore<Persistence> {
+        CachedStore {
+            inner,
+            cache: Mutex::new(Lru::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+    /// The store behind this cache. Reading from it directly doesn't count as a hit or miss.
+    pub fn inner(&self) -> &Persistence {
+        &self.inner
+    }
+    /// How many fetches were answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    /// How many fetches had to go to the inner store
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+    /// How many nodes are currently cached
+    pub fn len(&self) -> usize {
+        self.cache().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn cache(&self) -> MutexGuard<'_, Lru> {
+        // Entries are keyed by their own hash, so a cached node is never wrong, at worst it
+        // was already deleted from the inner store. A panic midway through an update can't
+        // do worse than that, so we keep using a poisoned cache.
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Looks for `hash` in the cache, falling back to `fetch` and caching what it returns
+    fn fetch<T>(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+        cached: impl Fn(Node) -> Option<T>,
+        fetch: impl FnOnce() -> Result<Option<T>, Persistence::Error>,
+        into_node: impl Fn(T) -> Node,
+    ) -> Result<Option<T>, Persistence::Error>
+    where
+        T: Clone,
+    {
+        if let Some(node) = self.cache().get(namespace, &hash).and_then(cached) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(node));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let node = fetch()?;
+        if let Some(node) = &node {
+            self.cache().put(namespace, hash, into_node(node.clone()));
+        }
+        Ok(node)
+    }
+}
+
+impl<Persistence: TreeStore> TreeStore for CachedStore<Persistence> {
+    type Error = Persistence::Error;
+
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        self.inner.apply_batch(namespace, ops.clone())?;
+        let mut cache = self.cache();
+        for op in ops {
+            match op {
+                Op::InsertBranch(branch) => {
+                    cache.put(namespace, branch.node_hash(), Node::Branch(branch));
+                }
+                Op::InsertLeaf(leaf) => cache.put(namespace, leaf.node_hash(), Node::Leaf(leaf)),
+                Op::DeleteBranch(hash) | Op::DeleteLeaf(hash) => cache.remove(namespace, &hash),
+                // We don't cache those
+                Op::InsertCompactedLeaf(_)
+                | Op::DeleteCompactedLeaf(_)
+                | Op::SetRoot(_)
+                | Op::SetRefCount(..) => {}
+            }
+        }
+        Ok(())
+    }
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        self.inner.get_root(namespace)
+    }
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        self.inner.get_ref_count(namespace, hash)
+    }
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertBranch(branch)])
+    }
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertLeaf(leaf)])
+    }
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteBranch(hash)])
+    }
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteLeaf(hash)])
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        self.fetch(
+            namespace,
+            hash,
+            |node| match node {
+                Node::Branch(branch) => Some(branch),
+                _ => None,
+            },
+            || self.inner.fetch_branch(namespace, hash),
+            Node::Branch,
+        )
+    }
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        self.fetch(
+            namespace,
+            hash,
+            |node| match node {
+                Node::Leaf(leaf) => Some(leaf),
+                _ => None,
+            },
+            || self.inner.fetch_leaf(namespace, hash),
+            Node::Leaf,
+        )
+    }
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertCompactedLeaf(leaf)])
+    }
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteCompactedLeaf(hash)])
+    }
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        self.inner.fetch_compacted_leaf(namespace, hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        memory_db::MemoryDatabase,
+        node::{LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        tree::{MSSMTree, Tree},
+        tree_backend::{TreeStore, DEFAULT_NAMESPACE},
+    };
+
+    use super::CachedStore;
+
+    #[test]
+    fn test_store() {
+        crate::mssmt::tree_backend::test::test_store(CachedStore::new(MemoryDatabase::new(), 16));
+    }
+
+    #[test]
+    fn test_hits() {
+        let storage = CachedStore::new(MemoryDatabase::new(), 1024);
+        let mut tree = MSSMTree::new(&storage);
+        for i in 0..3_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i], 1).unwrap();
+        }
+        let misses = storage.misses();
+        // Everything on the path was cached when it was written
+        tree.lookup(NodeHash::from([1; 32])).unwrap().unwrap();
+        tree.lookup(NodeHash::from([2; 32])).unwrap().unwrap();
+        assert_eq!(storage.misses(), misses);
+        assert!(storage.hits() > 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let storage = CachedStore::new(MemoryDatabase::new(), 2);
+        let leaves: Vec<_> = (0..3_u8).map(|i| LeafNode::new(vec![i], 1)).collect();
+        for leaf in &leaves {
+            storage
+                .insert_leaf(DEFAULT_NAMESPACE, leaf.clone())
+                .unwrap();
+        }
+        assert_eq!(storage.len(), 2);
+
+        // The first leaf was the least recently used
+        storage
+            .fetch_leaf(DEFAULT_NAMESPACE, leaves[0].node_hash())
+            .unwrap()
+            .unwrap();
+        assert_eq!((storage.hits(), storage.misses()), (0, 1));
+        // Now the second one is
+        storage
+            .fetch_leaf(DEFAULT_NAMESPACE, leaves[2].node_hash())
+            .unwrap()
+            .unwrap();
+        storage
+            .fetch_leaf(DEFAULT_NAMESPACE, leaves[1].node_hash())
+            .unwrap()
+            .unwrap();
+        assert_eq!((storage.hits(), storage.misses()), (1, 2));
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn test_deletes() {
+        let storage = CachedStore::new(MemoryDatabase::new(), 16);
+        let leaf = LeafNode::new(vec![1], 1);
+        storage
+            .insert_leaf(DEFAULT_NAMESPACE, leaf.clone())
+            .unwrap();
+        storage.insert_leaf("other", leaf.clone()).unwrap();
+        storage
+            .delete_leaf(DEFAULT_NAMESPACE, leaf.node_hash())
+            .unwrap();
+
+        let found = storage
+            .fetch_leaf(DEFAULT_NAMESPACE, leaf.node_hash())
+            .unwrap();
+        assert!(found.is_none());
+        assert!(storage
+            .fetch_leaf("other", leaf.node_hash())
+            .unwrap()
+            .is_some());
+        assert_eq!(storage.len(), 1);
+        assert!(storage
+            .inner()
+            .fetch_leaf("other", leaf.node_hash())
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,518 @@
+//! A compacted Merkle-Sum Sparse Merkle Tree. This is the same tree as [MSSMTree], with the
+//! same roots and proofs, but stored in a smarter way. In a full tree, every leaf needs all
+//! 256 branches between it and the root. But most of those branches have an empty sibling,
+//! because the tree is sparse. Here, a leaf is stored at the shortest prefix of it's key that
+//! no other leaf shares, as a [CompactedLeafNode]. So a tree with `n` leaves stores roughly
+//! `log2(n)` branches per leaf. This is the same as `CompactedTree` from lightninglabs/taro.
+//!
+//! [MSSMTree]: super::tree::MSSMTree
+use super::{
+    error::{Error, SumOverflow},
+    node::{CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode, Node},
+    node_hash::NodeHash,
+    proof::{Proof, Provable},
+    tree::{empty_hashes, empty_tree, BatchChanges, Tree},
+    tree_backend::{TreeStore, DEFAULT_NAMESPACE},
+};
+
+/// Everything that can live under a branch in a compacted tree
+enum Child {
+    /// An empty subtree, that isn't stored
+    Empty,
+    Branch(DiskBranchNode),
+    Leaf(CompactedLeafNode),
+}
+
+/// A Merkle-Sum Sparse Merkle Tree that stores each leaf as a [CompactedLeafNode], right
+/// below the last branch it shares with another leaf. Roots and proofs are the same as a
+/// [MSSMTree](super::tree::MSSMTree) with the same leaves, but storage is about `log2(n)`
+/// branches per leaf, instead of 256.
+pub struct CompactedMSSMTree<Persistence: TreeStore> {
+    /// A backend for our tree. We store nodes in key-value pairs.
+    database: Persistence,
+    /// Points to this tree's root
+    root: NodeHash,
+    /// The pre-computed values for an empty tree
+    empty_tree: &'static [Node],
+    /// The hashes of `empty_tree`, to check if a subtree is empty
+    empty_hashes: &'static [NodeHash],
+    /// Where our nodes live inside the storage
+    namespace: String,
+}
+
+impl<Persistence: TreeStore> CompactedMSSMTree<Persistence> {
+    /// Creates an empty tree in the [DEFAULT_NAMESPACE]
+    pub fn new(database: Persistence) -> CompactedMSSMTree<Persistence> {
+        CompactedMSSMTree::with_namespace(database, DEFAULT_NAMESPACE)
+    }
+    /// Creates an empty tree that keeps its nodes in `namespace`, see
+    /// [MSSMTree::with_namespace](super::tree::MSSMTree::with_namespace)
+    pub fn with_namespace(
+        database: Persistence,
+        namespace: &str,
+    ) -> CompactedMSSMTree<Persistence> {
+        CompactedMSSMTree {
+            database,
+            root: empty_hashes()[0],
+            empty_tree: empty_tree(),
+            empty_hashes: empty_hashes(),
+            namespace: namespace.to_owned(),
+        }
+    }
+    /// Returns the hash of this tree's root
+    pub fn root_hash(&self) -> NodeHash {
+        self.root
+    }
+    /// Returns this tree's root node, that also holds the sum of all leaves
+    pub fn root(&self) -> Result<Node, Error<Persistence::Error>> {
+        let root = self
+            .database
+            .fetch_branch(&self.namespace, self.root)
+            .map_err(Error::Storage)?;
+        match root {
+            Some(root) => Ok(Node::Branch(root)),
+            None => Ok(self.empty_tree[0].clone()),
+        }
+    }
+    /// Finds out what lives at `hash`, that is a node at depth `idx`
+    fn fetch_child(&self, hash: NodeHash, idx: usize) -> Result<Child, Error<Persistence::Error>> {
+        if hash == self.empty_hashes[idx] {
+            return Ok(Child::Empty);
+        }
+        if let Some(branch) = self
+            .database
+            .fetch_branch(&self.namespace, hash)
+            .map_err(Error::Storage)?
+        {
+            return Ok(Child::Branch(branch));
+        }
+        if let Some(leaf) = self
+            .database
+            .fetch_compacted_leaf(&self.namespace, hash)
+            .map_err(Error::Storage)?
+        {
+            return Ok(Child::Leaf(leaf));
+        }
+        Ok(Child::Empty)
+    }
+    /// Returns the children of the branch at `hash`, that is a node at depth `idx`
+    fn get_children_hash(
+        &self,
+        hash: NodeHash,
+        idx: usize,
+    ) -> Result<(NodeHash, NodeHash), Error<Persistence::Error>> {
+        if let Child::Branch(branch) = self.fetch_child(hash, idx)? {
+            return Ok((*branch.l_child(), *branch.r_child()));
+        }
+        let hash = self.empty_hashes[idx + 1];
+        Ok((hash, hash))
+    }
+    /// Returns the node we should use for `child`, a child at depth `idx`
+    fn child_node(&self, child: Child, idx: usize) -> Node {
+        match child {
+            Child::Empty => self.empty_tree[idx].clone(),
+            Child::Branch(branch) => Node::Branch(branch),
+            Child::Leaf(leaf) => Node::Compacted(leaf),
+        }
+    }
+    /// Inserts `leaf` into the subtree rooted at `node`, that sits at depth `idx`, returning
+    /// the new subtree root. If that root is a compacted leaf it isn't in `changes` yet,
+    /// because our caller may still move it further up.
+    fn insert_at(
+        &self,
+        key: NodeHash,
+        leaf: &LeafNode,
+        idx: usize,
+        node: NodeHash,
+        changes: &mut BatchChanges<CompactedLeafNode>,
+    ) -> Result<Node, Error<Persistence::Error>> {
+        let (left, right) = self.get_children_hash(node, idx)?;
+        let (next, sibling) = if key.bit_index(idx as u8) {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let is_empty = leaf.node_hash() == self.empty_hashes[256];
+
+        let new_child = match self.fetch_child(next, idx + 1)? {
+            // An empty subtree, we can put our leaf right here
+            Child::Empty if is_empty => self.empty_tree[idx + 1].clone(),
+            Child::Empty => Node::Compacted(CompactedLeafNode::new(idx + 1, key, leaf.clone())),
+            Child::Branch(_) => self.insert_at(key, leaf, idx + 1, next, changes)?,
+            // We are replacing this leaf
+            Child::Leaf(old) if *old.key() == key => {
+                changes.deleted_leaves.push(old.node_hash());
+                if is_empty {
+                    self.empty_tree[idx + 1].clone()
+                } else {
+                    Node::Compacted(CompactedLeafNode::new(idx + 1, key, leaf.clone()))
+                }
+            }
+            // Deleting a key that isn't here, there's nothing to do
+            Child::Leaf(_) if is_empty => {
+                return Ok(self.child_node(self.fetch_child(node, idx)?, idx))
+            }
+            // Some other leaf lives here, we need to split them into a new subtree
+            Child::Leaf(old) => {
+                changes.deleted_leaves.push(old.node_hash());
+                self.merge(idx + 1, key, leaf.clone(), old, changes)?
+            }
+        };
+        let sibling = self.child_node(self.fetch_child(sibling, idx + 1)?, idx + 1);
+
+        // If the old node isn't empty, delete it from the storage
+        if node != self.empty_hashes[idx] {
+            changes.deleted_branches.push(node);
+        }
+        // A branch with a single leaf under it is stored as that leaf, one level up. This
+        // only happens after a deletion, and the root always stays a branch.
+        if idx > 0 {
+            let empty = self.empty_hashes[idx + 1];
+            let lone_leaf = match (&new_child, &sibling) {
+                (Node::Compacted(leaf), sibling) if sibling.node_hash() == empty => Some(leaf),
+                (new_child, Node::Compacted(leaf)) if new_child.node_hash() == empty => {
+                    // Our sibling was already stored, at it's old height
+                    changes.deleted_leaves.push(leaf.node_hash());
+                    Some(leaf)
+                }
+                _ => None,
+            };
+            if let Some(leaf) = lone_leaf {
+                let leaf = CompactedLeafNode::new(idx, *leaf.key(), leaf.leaf().clone());
+                return Ok(Node::Compacted(leaf));
+            }
+        }
+        if let Node::Compacted(leaf) = &new_child {
+            changes.new_leaves.push(leaf.clone());
+        }
+
+        let sum = new_child
+            .node_sum()
+            .checked_add(sibling.node_sum())
+            .ok_or(SumOverflow)?;
+        let new_node = if key.bit_index(idx as u8) {
+            DiskBranchNode::new(sum, new_child.node_hash(), sibling.node_hash())
+        } else {
+            DiskBranchNode::new(sum, sibling.node_hash(), new_child.node_hash())
+        };
+
+        // If the new node isn't empty, add it into the storage
+        if new_node.node_hash() != self.empty_hashes[idx] {
+            changes.new_branches.push(new_node.clone());
+        }
+        Ok(Node::Branch(new_node))
+    }
+    /// Creates a subtree rooted at depth `idx` with two leaves. The leaves are stored right
+    /// below the point where their keys diverge, and the path between `idx` and this point
+    /// only has empty siblings.
+    fn merge(
+        &self,
+        idx: usize,
+        key: NodeHash,
+        leaf: LeafNode,
+        other: CompactedLeafNode,
+        changes: &mut BatchChanges<CompactedLeafNode>,
+    ) -> Result<Node, Error<Persistence::Error>> {
+        let prefix = common_prefix_len(&key, other.key());
+        let sum = leaf
+            .node_sum()
+            .checked_add(other.node_sum())
+            .ok_or(SumOverflow)?;
+
+        let new_leaf = CompactedLeafNode::new(prefix + 1, key, leaf);
+        let other = CompactedLeafNode::new(prefix + 1, *other.key(), other.leaf().clone());
+        let mut parent = if key.bit_index(prefix as u8) {
+            DiskBranchNode::new(sum, new_leaf.node_hash(), other.node_hash())
+        } else {
+            DiskBranchNode::new(sum, other.node_hash(), new_leaf.node_hash())
+        };
+        changes.new_leaves.push(new_leaf);
+        changes.new_leaves.push(other);
+        changes.new_branches.push(parent.clone());
+
+        for level in (idx..prefix).rev() {
+            let empty = self.empty_hashes[level + 1];
+            parent = if key.bit_index(level as u8) {
+                DiskBranchNode::new(sum, parent.node_hash(), empty)
+            } else {
+                DiskBranchNode::new(sum, empty, parent.node_hash())
+            };
+            changes.new_branches.push(parent.clone());
+        }
+        Ok(Node::Branch(parent))
+    }
+}
+
+/// How many bits, from the root down, `a` and `b` have in common
+fn common_prefix_len(a: &NodeHash, b: &NodeHash) -> usize {
+    (0..=255)
+        .take_while(|idx| a.bit_index(*idx) == b.bit_index(*idx))
+        .count()
+}
+
+impl<Persistence: TreeStore> Tree<Error<Persistence::Error>> for CompactedMSSMTree<Persistence> {
+    fn insert(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
+        let leaf = LeafNode::new(data, sum);
+        let mut changes = BatchChanges::default();
+        let new_root = self.insert_at(key, &leaf, 0, self.root, &mut changes)?;
+
+        self.database
+            .apply_batch(&self.namespace, changes.into_ops(false))
+            .map_err(Error::Storage)?;
+        self.root = new_root.node_hash();
+        Ok(())
+    }
+
+    fn delete(&mut self, key: NodeHash) -> Result<(), Error<Persistence::Error>> {
+        self.insert(key, vec![], 0)
+    }
+
+    fn update(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
+        self.insert(key, data, sum)
+    }
+
+    fn lookup(&self, key: NodeHash) -> Result<Option<LeafNode>, Error<Persistence::Error>> {
+        let mut node = self.root;
+        for idx in 0..=255 {
+            let (left, right) = self.get_children_hash(node, idx)?;
+            let next = if key.bit_index(idx as u8) {
+                left
+            } else {
+                right
+            };
+            match self.fetch_child(next, idx + 1)? {
+                Child::Empty => return Ok(None),
+                Child::Leaf(leaf) if *leaf.key() == key => return Ok(Some(leaf.leaf().clone())),
+                Child::Leaf(_) => return Ok(None),
+                Child::Branch(_) => node = next,
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<T: TreeStore> Provable for CompactedMSSMTree<T> {
+    type Error = Error<T::Error>;
+
+    fn prove(&self, key: NodeHash) -> Result<Proof, Self::Error> {
+        let mut proof = Vec::with_capacity(256);
+        let mut node = self.root;
+        // Walks down the stored branches, until we reach a leaf or an empty subtree
+        let mut bottom = None;
+        for idx in 0..=255 {
+            let (left, right) = self.get_children_hash(node, idx)?;
+            let (next, sibling) = if key.bit_index(idx as u8) {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            let sibling = self.fetch_child(sibling, idx + 1)?;
+            proof.push(self.child_node(sibling, idx + 1));
+
+            match self.fetch_child(next, idx + 1)? {
+                Child::Branch(_) => node = next,
+                Child::Empty => break,
+                Child::Leaf(leaf) => {
+                    bottom = Some(leaf);
+                    break;
+                }
+            }
+        }
+        // Below this point, the only thing that isn't empty is a compacted leaf. If it isn't
+        // our key, it becomes a sibling where both keys diverge.
+        let diverges_at = bottom
+            .filter(|leaf| *leaf.key() != key)
+            .map(|leaf| (common_prefix_len(&key, leaf.key()), leaf));
+        for idx in proof.len()..=255 {
+            match diverges_at {
+                Some((prefix, ref leaf)) if prefix == idx => {
+                    let sibling = CompactedLeafNode::new(idx + 1, *leaf.key(), leaf.leaf().clone());
+                    proof.push(Node::Compacted(sibling));
+                }
+                _ => proof.push(self.empty_tree[idx + 1].clone()),
+            }
+        }
+        Ok(Proof::new(proof))
+    }
+
+    fn prove_absence(&self, key: NodeHash) -> Result<Option<Proof>, Self::Error> {
+        if self.lookup(key)?.is_some() {
+            return Ok(None);
+        }
+        self.prove(key).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Digest;
+
+    use crate::mssmt::{
+        memory_db::MemoryDatabase,
+        node::{LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        proof::{Provable, Verifiable},
+        tree::{MSSMTree, Tree},
+    };
+
+    use super::CompactedMSSMTree;
+
+    fn get_keys() -> Vec<NodeHash> {
+        let mut keys: Vec<NodeHash> = (0..50_u8)
+            .map(|i| {
+                let key: [u8; 32] = sha2::Sha256::digest([i]).into();
+                key.into()
+            })
+            .collect();
+        // Two keys that only diverge on the very last bit
+        let mut key = [0xaa; 32];
+        keys.push(key.into());
+        key[31] ^= 0x80;
+        keys.push(key.into());
+        keys
+    }
+
+    #[test]
+    fn test_same_as_full_tree() {
+        let mut full = MSSMTree::new(MemoryDatabase::new());
+        let mut compacted = CompactedMSSMTree::new(MemoryDatabase::new());
+        let keys = get_keys();
+
+        for (i, key) in keys.iter().enumerate() {
+            full.insert(*key, vec![i as u8], i as u64).unwrap();
+            compacted.insert(*key, vec![i as u8], i as u64).unwrap();
+            assert_eq!(full.root_hash(), compacted.root_hash());
+        }
+        assert_eq!(
+            full.root().unwrap().node_sum(),
+            compacted.root().unwrap().node_sum()
+        );
+
+        for (i, key) in keys.iter().enumerate() {
+            let leaf = compacted
+                .lookup(*key)
+                .unwrap()
+                .expect("We've inserted this");
+            assert_eq!(
+                leaf.node_hash(),
+                LeafNode::new(vec![i as u8], i as u64).node_hash()
+            );
+
+            let full_proof = full.prove(*key).unwrap();
+            let proof = compacted.prove(*key).unwrap();
+            for (a, b) in full_proof.nodes().iter().zip(proof.nodes()) {
+                assert_eq!(a.node_hash(), b.node_hash());
+                assert_eq!(a.node_sum(), b.node_sum());
+            }
+            assert_eq!(proof.verify(&leaf, key).unwrap(), compacted.root_hash());
+        }
+
+        // Proofs of things that aren't there
+        let absent = NodeHash::from([0xab; 32]);
+        let proof = compacted.prove_absence(absent).unwrap().unwrap();
+        assert!(proof
+            .verify_non_inclusion(&absent, &compacted.root_hash())
+            .unwrap());
+        assert!(compacted.prove_absence(keys[0]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_delete_and_update() {
+        let mut full = MSSMTree::new(MemoryDatabase::new());
+        let mut compacted = CompactedMSSMTree::new(MemoryDatabase::new());
+        let keys = get_keys();
+        for (i, key) in keys.iter().enumerate() {
+            full.insert(*key, vec![i as u8], 1).unwrap();
+            compacted.insert(*key, vec![i as u8], 1).unwrap();
+        }
+        for (i, key) in keys.iter().enumerate().step_by(3) {
+            full.update(*key, vec![i as u8], 2).unwrap();
+            compacted.update(*key, vec![i as u8], 2).unwrap();
+        }
+        for key in keys.iter().step_by(2) {
+            full.delete(*key).unwrap();
+            compacted.delete(*key).unwrap();
+            assert!(compacted.lookup(*key).unwrap().is_none());
+        }
+        // Deleting something that isn't there is a no-op
+        let root = compacted.root_hash();
+        compacted.delete(NodeHash::from([0xab; 32])).unwrap();
+        assert_eq!(compacted.root_hash(), root);
+        assert_eq!(full.root_hash(), compacted.root_hash());
+
+        // Deletions leave the same nodes behind as only inserting what's left
+        let mut fresh = CompactedMSSMTree::new(MemoryDatabase::new());
+        for (i, key) in keys.iter().enumerate().skip(1).step_by(2) {
+            let leaf = compacted.lookup(*key).unwrap().unwrap();
+            assert_eq!(leaf.data(), &[i as u8]);
+            fresh
+                .insert(*key, leaf.data().to_vec(), leaf.node_sum())
+                .unwrap();
+        }
+        assert_eq!(fresh.root_hash(), compacted.root_hash());
+        assert_eq!(
+            fresh.database.len().unwrap(),
+            compacted.database.len().unwrap()
+        );
+
+        for key in keys.iter() {
+            full.delete(*key).unwrap();
+            compacted.delete(*key).unwrap();
+        }
+        assert_eq!(full.root_hash(), compacted.root_hash());
+        assert!(compacted.database.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_delete_collapses() {
+        let database = MemoryDatabase::new();
+        let mut full = MSSMTree::new(MemoryDatabase::new());
+        let mut compacted = CompactedMSSMTree::new(&database);
+        let keys = get_keys();
+        let (a, b) = (keys[50], keys[51]);
+        for (i, key) in [a, b].into_iter().enumerate() {
+            full.insert(key, vec![i as u8], 1).unwrap();
+            compacted.insert(key, vec![i as u8], 1).unwrap();
+        }
+        // Both leaves sit at the bottom, under 256 branches
+        assert_eq!(database.len().unwrap(), 258);
+
+        // The remaining leaf moves back up, right below the root
+        full.delete(a).unwrap();
+        compacted.delete(a).unwrap();
+        assert_eq!(database.len().unwrap(), 2);
+        assert_eq!(full.root_hash(), compacted.root_hash());
+        let leaf = compacted.lookup(b).unwrap().unwrap();
+        let proof = compacted.prove(b).unwrap();
+        assert_eq!(proof.verify(&leaf, &b).unwrap(), compacted.root_hash());
+
+        // And can be split again
+        full.insert(a, vec![2], 2).unwrap();
+        compacted.insert(a, vec![2], 2).unwrap();
+        assert_eq!(database.len().unwrap(), 258);
+        assert_eq!(full.root_hash(), compacted.root_hash());
+    }
+
+    #[test]
+    fn test_storage_usage() {
+        let full = MemoryDatabase::new();
+        let compacted = MemoryDatabase::new();
+        let mut full_tree = MSSMTree::new(&full);
+        let mut compacted_tree = CompactedMSSMTree::new(&compacted);
+        for (i, key) in get_keys().into_iter().take(50).enumerate() {
+            full_tree.insert(key, vec![i as u8], 1).unwrap();
+            compacted_tree.insert(key, vec![i as u8], 1).unwrap();
+        }
+        assert!(compacted.len().unwrap() * 10 < full.len().unwrap());
+    }
+}
@@ -0,0 +1,290 @@
+//! Finds which leaves changed between two roots in the same storage. If two subtrees have the
+//! same hash they hold the same leaves, so we only descend into subtrees whose hash differs
+//! on each side. The cost is proportional to the number of changes, not the size of the
+//! trees.
+//!
+//! Both roots must be in the storage, e.g. by building the tree with
+//! [MSSMTree::with_history](super::tree::MSSMTree::with_history). Changes are yielded in the
+//! order they appear in the tree, see [NodeHash::cmp_path].
+use super::{
+    error::Error,
+    iter::set_bit,
+    node::{DiskBranchNode, LeafNode, MSSMTNode},
+    node_hash::NodeHash,
+    tree::{check_depth, empty_hashes_with_depth},
+    tree_backend::{TreeStore, DEFAULT_NAMESPACE},
+};
+
+/// A leaf that differs between two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// This key is only in the new tree
+    Added { key: NodeHash, leaf: LeafNode },
+    /// This key is only in the old tree
+    Removed { key: NodeHash, leaf: LeafNode },
+    /// This key is in both trees, with different values
+    Modified {
+        key: NodeHash,
+        old: LeafNode,
+        new: LeafNode,
+    },
+}
+
+impl Change {
+    /// The key of the leaf that changed
+    pub fn key(&self) -> &NodeHash {
+        match self {
+            Change::Added { key, .. } => key,
+            Change::Removed { key, .. } => key,
+            Change::Modified { key, .. } => key,
+        }
+    }
+    /// The leaf's sum in the old tree, zero if it was added
+    pub fn old_sum(&self) -> u64 {
+        match self {
+            Change::Added { .. } => 0,
+            Change::Removed { leaf, .. } => leaf.node_sum(),
+            Change::Modified { old, .. } => old.node_sum(),
+        }
+    }
+    /// The leaf's sum in the new tree, zero if it was removed
+    pub fn new_sum(&self) -> u64 {
+        match self {
+            Change::Added { leaf, .. } => leaf.node_sum(),
+            Change::Removed { .. } => 0,
+            Change::Modified { new, .. } => new.node_sum(),
+        }
+    }
+}
+
+/// Returns all changes needed to go from the tree at `old_root` to the tree at `new_root`
+pub fn diff<Persistence: TreeStore>(
+    database: &Persistence,
+    old_root: NodeHash,
+    new_root: NodeHash,
+) -> Diff<'_, Persistence> {
+    diff_in(database, DEFAULT_NAMESPACE, old_root, new_root)
+}
+
+/// Like [diff], for two roots whose nodes live in `namespace`
+pub fn diff_in<'a, Persistence: TreeStore>(
+    database: &'a Persistence,
+    namespace: &'a str,
+    old_root: NodeHash,
+    new_root: NodeHash,
+) -> Diff<'a, Persistence> {
+    diff_with_depth(database, namespace, old_root, new_root)
+}
+
+/// Like [diff_in], for two trees with `DEPTH` levels, e.g.
+/// `diff_with_depth::<_, 32>(&database, "index", old_root, new_root)`
+pub fn diff_with_depth<'a, Persistence: TreeStore, const DEPTH: usize>(
+    database: &'a Persistence,
+    namespace: &'a str,
+    old_root: NodeHash,
+    new_root: NodeHash,
+) -> Diff<'a, Persistence, DEPTH> {
+    check_depth::<DEPTH>();
+    Diff {
+        database,
+        namespace,
+        empty_hashes: empty_hashes_with_depth::<DEPTH>(),
+        stack: vec![(old_root, new_root, 0, NodeHash::default())],
+    }
+}
+
+/// An iterator over the [Change]s between two trees, created by [diff]. `DEPTH` is the depth
+/// of both trees.
+pub struct Diff<'a, Persistence: TreeStore, const DEPTH: usize = 256> {
+    database: &'a Persistence,
+    namespace: &'a str,
+    empty_hashes: &'static [NodeHash],
+    /// Pairs of subtrees we still need to compare, as their old and new hashes, their depth,
+    /// and the key bits we've taken to reach them. The next pair is on the top.
+    stack: Vec<(NodeHash, NodeHash, usize, NodeHash)>,
+}
+
+impl<'a, Persistence: TreeStore, const DEPTH: usize> Diff<'a, Persistence, DEPTH> {
+    fn fetch_leaf(&self, hash: NodeHash) -> Result<Option<LeafNode>, Error<Persistence::Error>> {
+        if hash == self.empty_hashes[DEPTH] {
+            return Ok(None);
+        }
+        match self
+            .database
+            .fetch_leaf(self.namespace, hash)
+            .map_err(Error::Storage)?
+        {
+            Some(leaf) => Ok(Some(leaf)),
+            None => Err(Error::MissingNode(hash)),
+        }
+    }
+    fn fetch_branch(
+        &self,
+        hash: NodeHash,
+        idx: usize,
+    ) -> Result<DiskBranchNode, Error<Persistence::Error>> {
+        if hash == self.empty_hashes[idx] {
+            let child = self.empty_hashes[idx + 1];
+            return Ok(DiskBranchNode::new(0, child, child));
+        }
+        self.database
+            .fetch_branch(self.namespace, hash)
+            .map_err(Error::Storage)?
+            .ok_or(Error::MissingNode(hash))
+    }
+    /// Compares the next pair of subtrees, returning a change if we reached a leaf
+    fn step(
+        &mut self,
+        old: NodeHash,
+        new: NodeHash,
+        idx: usize,
+        key: NodeHash,
+    ) -> Result<Option<Change>, Error<Persistence::Error>> {
+        if idx == DEPTH {
+            let change = match (self.fetch_leaf(old)?, self.fetch_leaf(new)?) {
+                (None, Some(leaf)) => Change::Added { key, leaf },
+                (Some(leaf), None) => Change::Removed { key, leaf },
+                (Some(old), Some(new)) => Change::Modified { key, old, new },
+                (None, None) => return Ok(None),
+            };
+            return Ok(Some(change));
+        }
+        let old = self.fetch_branch(old, idx)?;
+        let new = self.fetch_branch(new, idx)?;
+
+        // Right goes first, so we visit left before it
+        let mut right_key = key;
+        set_bit(&mut right_key, idx);
+        self.stack
+            .push((*old.r_child(), *new.r_child(), idx + 1, right_key));
+        self.stack
+            .push((*old.l_child(), *new.l_child(), idx + 1, key));
+        Ok(None)
+    }
+}
+
+impl<'a, Persistence: TreeStore, const DEPTH: usize> Iterator for Diff<'a, Persistence, DEPTH> {
+    type Item = Result<Change, Error<Persistence::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((old, new, idx, key)) = self.stack.pop() {
+            // Same hash, same leaves
+            if old == new {
+                continue;
+            }
+            match self.step(old, new, idx, key) {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        error::Error,
+        memory_db::MemoryDatabase,
+        node::LeafNode,
+        node_hash::NodeHash,
+        ref_count::RefCountedStore,
+        tree::{MSSMTree, Tree},
+    };
+
+    use super::{diff, diff_with_depth, Change};
+
+    #[test]
+    fn test_diff() {
+        let database = MemoryDatabase::new();
+        let mut tree = MSSMTree::with_history(&database);
+        for i in 0..10_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i], i as u64)
+                .unwrap();
+        }
+        let old_root = tree.root_hash();
+
+        tree.insert(NodeHash::from([20; 32]), vec![20], 20).unwrap();
+        tree.delete(NodeHash::from([3; 32])).unwrap();
+        tree.update(NodeHash::from([5; 32]), vec![50], 50).unwrap();
+        let new_root = tree.root_hash();
+
+        let mut changes: Vec<_> = diff(&database, old_root, new_root)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        changes.sort_by(|a, b| a.key().cmp(b.key()));
+        assert_eq!(
+            changes,
+            vec![
+                Change::Removed {
+                    key: NodeHash::from([3; 32]),
+                    leaf: LeafNode::new(vec![3], 3),
+                },
+                Change::Modified {
+                    key: NodeHash::from([5; 32]),
+                    old: LeafNode::new(vec![5], 5),
+                    new: LeafNode::new(vec![50], 50),
+                },
+                Change::Added {
+                    key: NodeHash::from([20; 32]),
+                    leaf: LeafNode::new(vec![20], 20),
+                },
+            ]
+        );
+        assert_eq!(changes[1].old_sum(), 5);
+        assert_eq!(changes[1].new_sum(), 50);
+
+        // Going backwards swaps additions and removals
+        let backwards: Vec<_> = diff(&database, new_root, old_root)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(backwards.len(), 3);
+        assert!(backwards
+            .iter()
+            .any(|change| matches!(change, Change::Added { key, .. } if *key == [3; 32].into())));
+
+        assert_eq!(diff(&database, new_root, new_root).count(), 0);
+    }
+
+    #[test]
+    fn test_shallow_diff() {
+        // Two trees sharing their nodes, counted so one can't delete the other's
+        let database = RefCountedStore::new(MemoryDatabase::new());
+        let mut old = MSSMTree::<_, 8>::with_depth(&database, "shallow");
+        let mut new = MSSMTree::<_, 8>::with_depth(&database, "shallow");
+        old.insert(NodeHash::from([1; 32]), vec![1], 1).unwrap();
+        new.insert(NodeHash::from([1; 32]), vec![1], 1).unwrap();
+        new.insert(NodeHash::from([2; 32]), vec![2], 2).unwrap();
+        let (old_root, new_root) = (old.root_hash(), new.root_hash());
+
+        // Only the first 8 bits of a key place it in the tree
+        let mut key = [0; 32];
+        key[0] = 2;
+        let changes: Vec<_> = diff_with_depth::<_, 8>(&database, "shallow", old_root, new_root)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![Change::Added {
+                key: key.into(),
+                leaf: LeafNode::new(vec![2], 2),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_missing_root() {
+        let database = MemoryDatabase::new();
+        let tree = MSSMTree::new(&database);
+        let mut changes = diff(&database, tree.root_hash(), NodeHash::from([1; 32]));
+        assert!(matches!(
+            changes.next(),
+            Some(Err(Error::MissingNode(hash))) if hash == [1; 32].into()
+        ));
+        assert!(changes.next().is_none());
+    }
+}
@@ -0,0 +1,70 @@
+//! Errors returned by the Merkle-Sum Sparse Merkle Tree. Everything in this module returns
+//! an [Error], so callers can match on what went wrong. `StorageError` is the error type of
+//! the [TreeStore](super::tree_backend::TreeStore) backing a tree, things that never touch
+//! the storage, like parsing a hash or verifying a proof, use the default [Infallible].
+
+use std::{
+    convert::Infallible,
+    fmt::{Debug, Display},
+};
+
+use super::node_hash::NodeHash;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error<StorageError = Infallible> {
+    /// A hash doesn't have 32 bytes (or 64 hex characters)
+    InvalidHashLength(usize),
+    /// A hash string isn't valid hex
+    InvalidHex(hex::FromHexError),
+    /// A proof has less nodes than the tree's depth
+    ShortProof(usize),
+    /// A proof has more nodes than the tree's depth
+    LongProof(usize),
+    /// A compressed proof doesn't have one node for each non-empty sibling
+    ProofNodeMismatch { expected: usize, found: usize },
+    /// The same key was given twice, where keys should be unique
+    DuplicateKey(NodeHash),
+    /// The sum of a node and it's sibling doesn't fit into a u64
+    SumOverflow,
+    /// Tried to change a tree opened at a past root
+    ReadOnly,
+    /// The root we are trying to open isn't in the storage
+    UnknownRoot(NodeHash),
+    /// A node reachable from the root isn't in the storage
+    MissingNode(NodeHash),
+    /// The backend failed
+    Storage(StorageError),
+}
+
+impl<StorageError: Debug> Display for Error<StorageError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidHashLength(len) => write!(f, "Invalid length {len}"),
+            Error::InvalidHex(e) => write!(f, "Invalid hex {e}"),
+            Error::ShortProof(len) => write!(f, "Proof is too short ({len} nodes)"),
+            Error::LongProof(len) => write!(f, "Proof is too long ({len} nodes)"),
+            Error::ProofNodeMismatch { expected, found } => {
+                write!(f, "Proof should have {expected} nodes, but has {found}")
+            }
+            Error::DuplicateKey(key) => write!(f, "Duplicated key {key}"),
+            Error::SumOverflow => write!(f, "Sum overflows a u64"),
+            Error::ReadOnly => write!(f, "Tree is read-only"),
+            Error::UnknownRoot(root) => write!(f, "Unknown root {root}"),
+            Error::MissingNode(hash) => write!(f, "Missing node {hash}"),
+            Error::Storage(e) => write!(f, "Storage error {e:?}"),
+        }
+    }
+}
+
+impl<StorageError: Debug> std::error::Error for Error<StorageError> {}
+
+impl<StorageError> From<SumOverflow> for Error<StorageError> {
+    fn from(_: SumOverflow) -> Self {
+        Error::SumOverflow
+    }
+}
+
+/// Adding two sums together doesn't fit into a u64. This should never happen with honest
+/// amounts, so it means someone is trying to inflate the tree's sum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SumOverflow;
@@ -0,0 +1,120 @@
+//! Iterates over all leaves in a tree, from left to right. Leaves don't know their own key,
+//! so we rebuild it from the path we took to reach them. Empty subtrees are never stored, so
+//! we can skip them without looking any further, making this proportional to the number of
+//! leaves, rather than the size of the keyspace.
+//!
+//! Keys are yielded in the order they appear in the tree, see [NodeHash::cmp_path]. That's
+//! also the order used by range bounds.
+//!
+//! In trees shallower than 256 levels, only the first bits of a key are part of the path, so
+//! the keys we yield have all the other bits unset.
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+};
+
+use super::{error::Error, node::LeafNode, node_hash::NodeHash, tree_backend::TreeStore};
+
+/// An iterator over all leaves in a tree, whose key is inside a range. Created by
+/// [MSSMTree::iter](super::tree::MSSMTree::iter) and
+/// [MSSMTree::range](super::tree::MSSMTree::range).
+pub struct Leaves<'a, Persistence: TreeStore, Range: RangeBounds<NodeHash>> {
+    database: &'a Persistence,
+    namespace: &'a str,
+    empty_hashes: &'a [NodeHash],
+    /// How deep the tree is, leaves are at this depth
+    depth: usize,
+    range: Range,
+    /// Subtrees we still need to visit, as their hash, depth and the key bits we've taken
+    /// to reach them. The next subtree to visit is on the top.
+    stack: Vec<(NodeHash, usize, NodeHash)>,
+}
+
+impl<'a, Persistence: TreeStore, Range: RangeBounds<NodeHash>> Leaves<'a, Persistence, Range> {
+    pub(super) fn new(
+        database: &'a Persistence,
+        namespace: &'a str,
+        empty_hashes: &'a [NodeHash],
+        root: NodeHash,
+        range: Range,
+    ) -> Leaves<'a, Persistence, Range> {
+        Leaves {
+            database,
+            namespace,
+            empty_hashes,
+            // The empty tree has one node for each level, and the empty leaf
+            depth: empty_hashes.len() - 1,
+            range,
+            stack: vec![(root, 0, NodeHash::default())],
+        }
+    }
+    /// Whether the subtree at depth `idx`, reached through `prefix`, may have keys inside
+    /// our range. `prefix` only has the first `idx` bits set, so it's the smallest key in
+    /// this subtree.
+    fn overlaps(&self, prefix: &NodeHash, idx: usize) -> bool {
+        let mut max = *prefix;
+        for bit in idx..self.depth {
+            set_bit(&mut max, bit);
+        }
+        let after_start = match self.range.start_bound() {
+            Bound::Included(start) => max.cmp_path(start) != Ordering::Less,
+            Bound::Excluded(start) => max.cmp_path(start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        };
+        let before_end = match self.range.end_bound() {
+            Bound::Included(end) => prefix.cmp_path(end) != Ordering::Greater,
+            Bound::Excluded(end) => prefix.cmp_path(end) == Ordering::Less,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+/// Marks the `idx`-th step of `key`'s path as going right
+pub(super) fn set_bit(key: &mut NodeHash, idx: usize) {
+    key[idx / 8] |= 1 << (idx % 8);
+}
+
+impl<'a, Persistence: TreeStore, Range: RangeBounds<NodeHash>> Iterator
+    for Leaves<'a, Persistence, Range>
+{
+    type Item = Result<(NodeHash, LeafNode), Error<Persistence::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, idx, key)) = self.stack.pop() {
+            if node == self.empty_hashes[idx] || !self.overlaps(&key, idx) {
+                continue;
+            }
+            if idx == self.depth {
+                match self.database.fetch_leaf(self.namespace, node) {
+                    Ok(Some(leaf)) => return Some(Ok((key, leaf))),
+                    Ok(None) => {
+                        self.stack.clear();
+                        return Some(Err(Error::MissingNode(node)));
+                    }
+                    Err(e) => {
+                        self.stack.clear();
+                        return Some(Err(Error::Storage(e)));
+                    }
+                }
+            }
+            let branch = match self.database.fetch_branch(self.namespace, node) {
+                Ok(Some(branch)) => branch,
+                Ok(None) => {
+                    self.stack.clear();
+                    return Some(Err(Error::MissingNode(node)));
+                }
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(Error::Storage(e)));
+                }
+            };
+            // Right goes first, so we visit left before it
+            let mut right_key = key;
+            set_bit(&mut right_key, idx);
+            self.stack.push((*branch.r_child(), idx + 1, right_key));
+            self.stack.push((*branch.l_child(), idx + 1, key));
+        }
+        None
+    }
+}
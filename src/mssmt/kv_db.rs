@@ -0,0 +1,258 @@
+//! A persistent backend for trees, on top of [redb], an embedded key-value store written in
+//! pure Rust. Everything lives in a single file, and every write is a crash-safe
+//! transaction, so this works where shipping SQLite isn't an option, like mobile wallets.
+//!
+//! Each kind of node has it's own table, keyed by the namespace and the node's hash, so many
+//! trees can share a single file. Values are nodes in their canonical
+//! [encoding](super::encoding). Saved roots are keyed by namespace, and reference counts are
+//! keyed like nodes. Each batch of writes is a single redb transaction.
+//!
+//! # Usage:
+//! ```
+//!    use rust_taro::mssmt::{kv_db::KvTreeStore, node_hash::NodeHash};
+//!    use rust_taro::mssmt::tree::{MSSMTree, Tree};
+//!
+//!    let storage = KvTreeStore::in_memory().unwrap();
+//!    let mut tree = MSSMTree::new(storage);
+//!
+//!    tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+//!    assert!(tree.lookup(NodeHash::from([0; 32])).unwrap().is_some());
+//!```
+use std::path::Path;
+
+use redb::{backends::InMemoryBackend, Database, ReadableDatabase, TableDefinition};
+
+use super::{
+    encoding::{Decodable, DecodeError, Encodable},
+    node::{CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode},
+    node_hash::NodeHash,
+    tree_backend::{Op, TreeStore},
+};
+
+/// Nodes are keyed by `(namespace, hash)`
+type NodeTable = TableDefinition<'static, (&'static str, &'static [u8; 32]), &'static [u8]>;
+
+const BRANCHES: NodeTable = TableDefinition::new("mssmt_branches");
+const LEAVES: NodeTable = TableDefinition::new("mssmt_leaves");
+const COMPACTED_LEAVES: NodeTable = TableDefinition::new("mssmt_compacted_leaves");
+const ROOTS: TableDefinition<&str, &[u8; 32]> = TableDefinition::new("mssmt_roots");
+const REF_COUNTS: TableDefinition<(&str, &[u8; 32]), u64> =
+    TableDefinition::new("mssmt_ref_counts");
+
+pub struct KvTreeStore {
+    database: Database,
+}
+
+impl KvTreeStore {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<KvTreeStore, KvStoreError> {
+        let database = Database::create(path).map_err(redb::Error::from)?;
+        KvTreeStore::with_database(database)
+    }
+    /// Creates a new database that only lives in memory, mostly useful for tests
+    pub fn in_memory() -> Result<KvTreeStore, KvStoreError> {
+        let database = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .map_err(redb::Error::from)?;
+        KvTreeStore::with_database(database)
+    }
+    /// Uses an already open database, creating our tables if they don't exist yet
+    pub fn with_database(database: Database) -> Result<KvTreeStore, KvStoreError> {
+        let store = KvTreeStore { database };
+        store.create_tables()?;
+        Ok(store)
+    }
+    /// Reads only fail if a table doesn't exist, so we create all of them upfront
+    fn create_tables(&self) -> Result<(), redb::Error> {
+        let transaction = self.database.begin_write()?;
+        for table in [BRANCHES, LEAVES, COMPACTED_LEAVES] {
+            transaction.open_table(table)?;
+        }
+        transaction.open_table(ROOTS)?;
+        transaction.open_table(REF_COUNTS)?;
+        transaction.commit()?;
+        Ok(())
+    }
+    /// Writes `ops` into `namespace` inside a single transaction. redb only makes a
+    /// transaction visible once it's durably committed, so a crash either keeps all of them
+    /// or none.
+    fn write(&self, namespace: &str, ops: Vec<Op>) -> Result<(), redb::Error> {
+        let transaction = self.database.begin_write()?;
+        {
+            let mut branches = transaction.open_table(BRANCHES)?;
+            let mut leaves = transaction.open_table(LEAVES)?;
+            let mut compacted_leaves = transaction.open_table(COMPACTED_LEAVES)?;
+            let mut roots = transaction.open_table(ROOTS)?;
+            let mut ref_counts = transaction.open_table(REF_COUNTS)?;
+            for op in ops {
+                match op {
+                    Op::InsertBranch(branch) => {
+                        branches.insert(
+                            (namespace, &*branch.node_hash()),
+                            encode(&branch).as_slice(),
+                        )?;
+                    }
+                    Op::InsertLeaf(leaf) => {
+                        leaves.insert((namespace, &*leaf.node_hash()), encode(&leaf).as_slice())?;
+                    }
+                    Op::InsertCompactedLeaf(leaf) => {
+                        compacted_leaves
+                            .insert((namespace, &*leaf.node_hash()), encode(&leaf).as_slice())?;
+                    }
+                    Op::DeleteBranch(hash) => {
+                        branches.remove((namespace, &*hash))?;
+                    }
+                    Op::DeleteLeaf(hash) => {
+                        leaves.remove((namespace, &*hash))?;
+                    }
+                    Op::DeleteCompactedLeaf(hash) => {
+                        compacted_leaves.remove((namespace, &*hash))?;
+                    }
+                    Op::SetRoot(root) => {
+                        roots.insert(namespace, &*root)?;
+                    }
+                    Op::SetRefCount(hash, 0) => {
+                        ref_counts.remove((namespace, &*hash))?;
+                    }
+                    Op::SetRefCount(hash, count) => {
+                        ref_counts.insert((namespace, &*hash), count)?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+    fn get<Node: Decodable>(
+        &self,
+        table: NodeTable,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<Node>, KvStoreError> {
+        let transaction = self.database.begin_read().map_err(redb::Error::from)?;
+        let table = transaction.open_table(table).map_err(redb::Error::from)?;
+        match table.get((namespace, &*hash)).map_err(redb::Error::from)? {
+            Some(value) => Ok(Some(Node::decode(&mut value.value())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode(node: &impl Encodable) -> Vec<u8> {
+    let mut value = vec![];
+    node.encode(&mut value)
+        .expect("Writing into a Vec never fails");
+    value
+}
+
+impl TreeStore for KvTreeStore {
+    type Error = KvStoreError;
+
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        Ok(self.write(namespace, ops)?)
+    }
+
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        let transaction = self.database.begin_read().map_err(redb::Error::from)?;
+        let roots = transaction.open_table(ROOTS).map_err(redb::Error::from)?;
+        let root = roots.get(namespace).map_err(redb::Error::from)?;
+        Ok(root.map(|root| NodeHash::from(*root.value())))
+    }
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        let transaction = self.database.begin_read().map_err(redb::Error::from)?;
+        let ref_counts = transaction
+            .open_table(REF_COUNTS)
+            .map_err(redb::Error::from)?;
+        let count = ref_counts
+            .get((namespace, &**hash))
+            .map_err(redb::Error::from)?;
+        Ok(count.map(|count| count.value()).unwrap_or(0))
+    }
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertBranch(branch)])
+    }
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertLeaf(leaf)])
+    }
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteBranch(hash)])
+    }
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteLeaf(hash)])
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        self.get(BRANCHES, namespace, hash)
+    }
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        self.get(LEAVES, namespace, hash)
+    }
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertCompactedLeaf(leaf)])
+    }
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteCompactedLeaf(hash)])
+    }
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        self.get(COMPACTED_LEAVES, namespace, hash)
+    }
+}
+
+#[derive(Debug)]
+pub enum KvStoreError {
+    Redb(redb::Error),
+    /// A stored node isn't properly encoded, the database is probably corrupted
+    Decode(DecodeError),
+}
+impl From<redb::Error> for KvStoreError {
+    fn from(value: redb::Error) -> Self {
+        Self::Redb(value)
+    }
+}
+impl From<DecodeError> for KvStoreError {
+    fn from(value: DecodeError) -> Self {
+        Self::Decode(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        node::MSSMTNode,
+        node_hash::NodeHash,
+        tree::{MSSMTree, Tree},
+    };
+
+    use super::KvTreeStore;
+
+    #[test]
+    fn test_store() {
+        crate::mssmt::tree_backend::test::test_store(KvTreeStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("mssmt-{}.redb", std::process::id()));
+        let root = {
+            let mut tree = MSSMTree::open(KvTreeStore::open(&path).unwrap(), "assets").unwrap();
+            tree.insert(NodeHash::from([0; 32]), vec![0], 1).unwrap();
+            tree.root_hash()
+        };
+        let tree = MSSMTree::open(KvTreeStore::open(&path).unwrap(), "assets").unwrap();
+        assert_eq!(tree.root_hash(), root);
+        let leaf = tree.lookup(NodeHash::from([0; 32])).unwrap().unwrap();
+        assert_eq!(leaf.node_sum(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -5,16 +5,16 @@
 //!
 //! # Usage:
 //! ```
-//!    use rust_taro::{memory_db::MemoryDatabase, node::{MSSMTNode, LeafNode}};
-//!    use rust_taro::tree_backend::TreeStore;
+//!    use rust_taro::mssmt::{memory_db::MemoryDatabase, node::{MSSMTNode, LeafNode}};
+//!    use rust_taro::mssmt::tree_backend::TreeStore;
 //!
 //!    let storage = MemoryDatabase::new();
 //!
 //!    let leaf1 = LeafNode::new(vec![0, 1, 2, 3], 10);
-//!    storage.insert_leaf(leaf1.clone()).expect("Valid leaves");
+//!    storage.insert_leaf("assets", leaf1.clone()).expect("Valid leaves");
 //!
 //!    let branch = storage
-//!        .fetch_leaf(leaf1.node_hash())
+//!        .fetch_leaf("assets", leaf1.node_hash())
 //!        .unwrap()
 //!        .unwrap();
 //!
@@ -29,74 +29,215 @@ use std::{
     sync::{PoisonError, RwLock},
 };
 
+#[cfg(feature = "async")]
+use super::async_backend::AsyncTreeStore;
 use super::{
     node::MSSMTNode,
-    node::{BranchNode, DiskBranchNode, LeafNode, Node},
+    node::{CompactedLeafNode, DiskBranchNode, LeafNode, Node},
     node_hash::NodeHash,
-    tree_backend::TreeStore,
+    tree_backend::{Op, TreeStore},
 };
 
 #[derive(Debug)]
 pub struct MemoryDatabase {
-    inner: RwLock<HashMap<NodeHash, Node>>,
+    /// Stored nodes, by namespace
+    inner: RwLock<HashMap<String, HashMap<NodeHash, Node>>>,
+    /// Saved roots, by namespace
+    roots: RwLock<HashMap<String, NodeHash>>,
+    /// Saved reference counts, by namespace
+    ref_counts: RwLock<HashMap<String, HashMap<NodeHash, u64>>>,
 }
 
 impl MemoryDatabase {
     pub fn new() -> MemoryDatabase {
         MemoryDatabase {
             inner: RwLock::new(HashMap::new()),
+            roots: RwLock::new(HashMap::new()),
+            ref_counts: RwLock::new(HashMap::new()),
         }
     }
+    /// How many nodes are currently stored, in all namespaces
+    pub fn len(&self) -> Result<usize, MemoryDatabaseError> {
+        Ok(self.inner.read()?.values().map(HashMap::len).sum())
+    }
+    pub fn is_empty(&self) -> Result<bool, MemoryDatabaseError> {
+        Ok(self.len()? == 0)
+    }
+    /// Returns a copy of the node stored as `hash` inside `namespace`
+    fn get(&self, namespace: &str, hash: &NodeHash) -> Result<Option<Node>, MemoryDatabaseError> {
+        let inner = self.inner.read()?;
+        Ok(inner
+            .get(namespace)
+            .and_then(|nodes| nodes.get(hash))
+            .cloned())
+    }
+}
+
+impl Default for MemoryDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TreeStore for MemoryDatabase {
     type Error = MemoryDatabaseError;
 
-    fn delete_branch(&self, hash: NodeHash) -> Result<(), Self::Error> {
-        let mut inner = self.inner.write()?;
-        inner.remove(&hash);
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        // Nobody can see the maps until we release the locks, and nothing here can fail.
+        // Locks are always taken in this order, so we can't deadlock.
+        let mut namespaces = self.inner.write()?;
+        let mut roots = self.roots.write()?;
+        let mut all_ref_counts = self.ref_counts.write()?;
+        let inner = namespaces.entry(namespace.to_owned()).or_default();
+        let ref_counts = all_ref_counts.entry(namespace.to_owned()).or_default();
+        for op in ops {
+            match op {
+                Op::InsertBranch(branch) => {
+                    inner.insert(branch.node_hash(), Node::Branch(branch));
+                }
+                Op::InsertLeaf(leaf) => {
+                    inner.insert(leaf.node_hash(), Node::Leaf(leaf));
+                }
+                Op::InsertCompactedLeaf(leaf) => {
+                    inner.insert(leaf.node_hash(), Node::Compacted(leaf));
+                }
+                Op::DeleteBranch(hash) | Op::DeleteLeaf(hash) | Op::DeleteCompactedLeaf(hash) => {
+                    inner.remove(&hash);
+                }
+                Op::SetRoot(root) => {
+                    roots.insert(namespace.to_owned(), root);
+                }
+                Op::SetRefCount(hash, 0) => {
+                    ref_counts.remove(&hash);
+                }
+                Op::SetRefCount(hash, count) => {
+                    ref_counts.insert(hash, count);
+                }
+            }
+        }
+        if inner.is_empty() {
+            namespaces.remove(namespace);
+        }
+        if ref_counts.is_empty() {
+            all_ref_counts.remove(namespace);
+        }
         Ok(())
     }
 
-    fn delete_leaf(&self, hash: NodeHash) -> Result<(), Self::Error> {
-        let mut inner = self.inner.write()?;
-        inner.remove(&hash);
-        Ok(())
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        Ok(self.roots.read()?.get(namespace).copied())
     }
 
-    fn insert_branch(&self, branch: DiskBranchNode) -> Result<(), Self::Error> {
-        let mut inner = self.inner.write()?;
-        inner.insert(branch.node_hash(), Node::Branch(branch.into()));
-        Ok(())
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        let ref_counts = self.ref_counts.read()?;
+        Ok(ref_counts
+            .get(namespace)
+            .and_then(|counts| counts.get(hash))
+            .copied()
+            .unwrap_or(0))
     }
 
-    fn insert_leaf(&self, leaf: LeafNode) -> Result<(), Self::Error> {
-        let mut inner = self.inner.write()?;
-        inner.insert(leaf.node_hash(), Node::Leaf(leaf));
-        Ok(())
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteBranch(hash)])
     }
-    fn fetch_branch(&self, hash: NodeHash) -> Result<Option<DiskBranchNode>, Self::Error> {
-        let inner = self.inner.read()?;
-        let node = inner.get(&hash);
-        match node {
-            Some(Node::Branch(node)) => Ok(Some(node.to_owned())),
-            Some(Node::Leaf(_)) => Ok(None),
-            None => Ok(None),
+
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteLeaf(hash)])
+    }
+
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertBranch(branch)])
+    }
+
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertLeaf(leaf)])
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        match self.get(namespace, &hash)? {
+            Some(Node::Branch(node)) => Ok(Some(node)),
+            _ => Ok(None),
         }
     }
 
-    fn fetch_leaf(&self, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
-        let inner = self.inner.read()?;
-        let node = inner.get(&hash);
-        match node {
-            Some(Node::Branch(_)) => Ok(None),
-            Some(Node::Leaf(leaf)) => Ok(Some(leaf.to_owned())),
-            None => Ok(None),
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        match self.get(namespace, &hash)? {
+            Some(Node::Leaf(leaf)) => Ok(Some(leaf)),
+            _ => Ok(None),
         }
     }
 
-    fn fetch_branch_recursive(&self, _: NodeHash) -> Result<Option<BranchNode>, Self::Error> {
-        todo!()
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertCompactedLeaf(leaf)])
+    }
+
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteCompactedLeaf(hash)])
+    }
+
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        match self.get(namespace, &hash)? {
+            Some(Node::Compacted(leaf)) => Ok(Some(leaf)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// An async version of [MemoryDatabase]. Nothing here ever waits, so the async calls just
+/// run the sync ones.
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+pub struct AsyncMemoryDatabase {
+    inner: MemoryDatabase,
+}
+
+#[cfg(feature = "async")]
+impl AsyncMemoryDatabase {
+    pub fn new() -> AsyncMemoryDatabase {
+        AsyncMemoryDatabase {
+            inner: MemoryDatabase::new(),
+        }
+    }
+    /// Returns the sync database holding our nodes
+    pub fn inner(&self) -> &MemoryDatabase {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTreeStore for AsyncMemoryDatabase {
+    type Error = MemoryDatabaseError;
+
+    async fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        self.inner.apply_batch(namespace, ops)
+    }
+    async fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        self.inner.get_root(namespace)
+    }
+    async fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        self.inner.fetch_branch(namespace, hash)
+    }
+    async fn fetch_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<LeafNode>, Self::Error> {
+        self.inner.fetch_leaf(namespace, hash)
     }
 }
 
@@ -113,13 +254,16 @@ impl<T> From<PoisonError<T>> for MemoryDatabaseError {
 #[cfg(test)]
 mod test {
     use crate::mssmt::{
-        node::{DiskBranchNode, LeafNode, MSSMTNode},
+        node::{DiskBranchNode, LeafNode, MSSMTNode, Node},
         node_hash::NodeHash,
-        tree_backend::TreeStore,
+        tree::{MSSMTree, Tree},
+        tree_backend::{TreeStore, DEFAULT_NAMESPACE},
     };
 
     use super::MemoryDatabase;
 
+    const NS: &str = "test";
+
     #[test]
     fn test_database() {
         let storage = MemoryDatabase::new();
@@ -127,15 +271,20 @@ mod test {
         let leaf1 = LeafNode::new(vec![0, 1, 2, 3], 10);
         let leaf2 = LeafNode::new(vec![4, 5, 6], 100);
 
-        storage.insert_leaf(leaf1.clone()).expect("Valid leaves");
-        storage.insert_leaf(leaf2.clone()).expect("Valid leaves");
+        storage
+            .insert_leaf(NS, leaf1.clone())
+            .expect("Valid leaves");
+        storage
+            .insert_leaf(NS, leaf2.clone())
+            .expect("Valid leaves");
 
         let branch = DiskBranchNode::new(110, leaf1.node_hash(), leaf2.node_hash());
 
-        storage.insert_branch(branch).expect("Valid branch");
+        storage.insert_branch(NS, branch).expect("Valid branch");
 
         let branch = storage
             .fetch_branch(
+                NS,
                 NodeHash::try_from(
                     "9b70d7de4fe4c5b40347333d664073277251690e26df3270e84f3c73b6eec03c",
                 )
@@ -157,4 +306,46 @@ mod test {
             "a42280e0a6760328dfc8b4c494761c255c4aaa4f98d606eb52717dd872d3c15b"
         )
     }
+    #[test]
+    fn test_store() {
+        crate::mssmt::tree_backend::test::test_store(MemoryDatabase::new());
+    }
+    #[test]
+    fn test_fetch_branch_recursive() {
+        let storage = MemoryDatabase::new();
+        let mut tree = MSSMTree::new(&storage);
+        tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![2], 20).unwrap();
+        let root = tree.root().unwrap();
+
+        let subtree = storage
+            .fetch_branch_recursive(DEFAULT_NAMESPACE, root.node_hash(), usize::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(subtree.node_hash(), root.node_hash());
+        assert_eq!(subtree.node_sum(), 30);
+
+        // The first key goes left all the way down
+        let mut node = Node::Subtree(Box::new(subtree));
+        for _ in 0..256 {
+            node = match node {
+                Node::Subtree(branch) => branch.left().clone(),
+                _ => panic!("Should be a fully expanded branch"),
+            };
+        }
+        assert_eq!(node.node_hash(), LeafNode::new(vec![1], 10).node_hash());
+
+        // Branches at the limit only have their children hashes
+        let subtree = storage
+            .fetch_branch_recursive(DEFAULT_NAMESPACE, root.node_hash(), 1)
+            .unwrap()
+            .unwrap();
+        let Node::Subtree(left) = subtree.left() else {
+            panic!("Should be expanded");
+        };
+        assert!(matches!(left.left(), Node::Branch(_)));
+        // Empty subtrees aren't stored
+        assert!(matches!(left.right(), Node::Computed(_)));
+        assert_eq!(left.node_sum(), 10);
+    }
 }
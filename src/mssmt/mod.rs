@@ -1,8 +1,25 @@
+#[cfg(feature = "async")]
+pub mod async_backend;
+#[cfg(feature = "async")]
+pub mod async_tree;
+pub mod cache;
+pub mod compacted_tree;
+pub mod diff;
+pub mod encoding;
 pub mod error;
+pub mod iter;
+#[cfg(feature = "kv-db")]
+pub mod kv_db;
 #[cfg(any(feature = "memory-db", test))]
 pub mod memory_db;
+pub mod multi_proof;
 pub mod node;
 pub mod node_hash;
 pub mod proof;
+pub mod ref_count;
+#[cfg(feature = "sqlite-db")]
+pub mod sqlite_db;
 pub mod tree;
 pub mod tree_backend;
+
+pub use diff::{diff, diff_in};
@@ -0,0 +1,226 @@
+//! A proof for many leaves at once. Proofs for leaves in the same tree share most of their
+//! nodes, specially close to the root. If we prove `n` leaves one by one, we end up sending
+//! the same nodes `n` times. Worse, some of those nodes aren't needed at all, since they
+//! can be computed from other leaves being proven.
+//!
+//! A [MultiProof] only holds the siblings of the union of all paths, that is, the nodes that
+//! can't be computed from the leaves themselves. Like a
+//! [CompressedProof](super::proof::CompressedProof), empty siblings are replaced by a bit.
+//! Nodes are stored in the order we need them while hashing up, walking the paths from left
+//! to right.
+use super::{
+    error::Error,
+    node::{BranchNode, LeafNode, MSSMTNode, Node},
+    node_hash::NodeHash,
+    tree::empty_tree_with_depth,
+};
+
+/// `DEPTH` is the depth of the tree being proven, like in [Proof](super::proof::Proof)
+#[derive(Debug, Clone)]
+pub struct MultiProof<const DEPTH: usize = 256> {
+    /// One bit for each sibling, in the order they are used. If a bit is set, the sibling is
+    /// an empty subtree, and it isn't in `nodes`.
+    pub(super) bits: Vec<bool>,
+    /// All non-empty siblings, in the order they are used
+    pub(super) nodes: Vec<Node>,
+}
+
+/// Keeps track of the next sibling we should take from a [MultiProof]
+struct Cursor<'a, const DEPTH: usize> {
+    proof: &'a MultiProof<DEPTH>,
+    empty_tree: &'static [Node],
+    bit: usize,
+    node: usize,
+}
+
+impl<'a, const DEPTH: usize> Cursor<'a, DEPTH> {
+    /// Returns the next sibling, that lives at depth `idx`
+    fn next(&mut self, idx: usize) -> Result<Node, Error> {
+        let empty = *self
+            .proof
+            .bits
+            .get(self.bit)
+            .ok_or(Error::ShortProof(self.proof.bits.len()))?;
+        self.bit += 1;
+        if empty {
+            return Ok(self.empty_tree[idx].clone());
+        }
+        let node = self
+            .proof
+            .nodes
+            .get(self.node)
+            .ok_or(Error::ProofNodeMismatch {
+                expected: self.node + 1,
+                found: self.proof.nodes.len(),
+            })?;
+        self.node += 1;
+        Ok(node.clone())
+    }
+}
+
+impl<const DEPTH: usize> MultiProof<DEPTH> {
+    /// Returns how many non-empty nodes this proof has
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+    /// Hashes all leaves up to the root, returning the root's hash and sum. `leaves` may be
+    /// in any order, but each key must appear only once. Keys that aren't in the tree can be
+    /// proven using an empty leaf, [LeafNode::default].
+    pub fn root(&self, leaves: &[(NodeHash, LeafNode)]) -> Result<(NodeHash, u64), Error> {
+        let mut leaves = leaves.to_vec();
+        leaves.sort_by(|(a, _), (b, _)| a.cmp_path(b));
+        if let Some(pair) = leaves.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::DuplicateKey(pair[0].0));
+        }
+
+        let mut cursor = Cursor {
+            proof: self,
+            empty_tree: empty_tree_with_depth::<DEPTH>(),
+            bit: 0,
+            node: 0,
+        };
+        let root = hash_up(&leaves, 0, &mut cursor)?;
+
+        // Every node in the proof must be used, or this isn't a proof for those leaves
+        if cursor.bit != self.bits.len() {
+            return Err(Error::LongProof(self.bits.len()));
+        }
+        if cursor.node != self.nodes.len() {
+            return Err(Error::ProofNodeMismatch {
+                expected: cursor.node,
+                found: self.nodes.len(),
+            });
+        }
+        Ok((root.node_hash(), root.node_sum()))
+    }
+    /// Checks whether all `leaves` are in the tree with `expected_root`. Both the root hash
+    /// and sum must match.
+    pub fn verify(
+        &self,
+        leaves: &[(NodeHash, LeafNode)],
+        expected_root: &impl MSSMTNode,
+    ) -> Result<bool, Error> {
+        let (hash, sum) = self.root(leaves)?;
+        Ok(hash == expected_root.node_hash() && sum == expected_root.node_sum())
+    }
+}
+
+/// Computes the root of the subtree at depth `idx` containing `leaves`, taking whatever
+/// siblings we can't compute from `cursor`. This must walk the tree in the same order as
+/// [MSSMTree::prove_many](super::tree::MSSMTree::prove_many).
+fn hash_up<const DEPTH: usize>(
+    leaves: &[(NodeHash, LeafNode)],
+    idx: usize,
+    cursor: &mut Cursor<DEPTH>,
+) -> Result<Node, Error> {
+    if idx == DEPTH {
+        return Ok(Node::Leaf(leaves[0].1.clone()));
+    }
+    // Leaves going left come first, since they are sorted by path
+    let split = leaves.partition_point(|(key, _)| key.bit_index(idx as u8));
+    let (left_leaves, right_leaves) = leaves.split_at(split);
+
+    let left = if left_leaves.is_empty() {
+        cursor.next(idx + 1)?
+    } else {
+        hash_up(left_leaves, idx + 1, cursor)?
+    };
+    let right = if right_leaves.is_empty() {
+        cursor.next(idx + 1)?
+    } else {
+        hash_up(right_leaves, idx + 1, cursor)?
+    };
+    Ok(Node::Branch(BranchNode::new(left, right)?.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use sha2::Digest;
+
+    use crate::mssmt::{
+        error::Error,
+        memory_db::MemoryDatabase,
+        node::LeafNode,
+        node_hash::NodeHash,
+        proof::Provable,
+        tree::{MSSMTree, Tree},
+    };
+
+    fn get_key(i: u8) -> NodeHash {
+        let key: [u8; 32] = sha2::Sha256::digest([i]).into();
+        key.into()
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let mut tree = MSSMTree::new(MemoryDatabase::new());
+        for i in 0..100_u8 {
+            tree.insert(get_key(i), vec![i], i as u64).unwrap();
+        }
+        let root = tree.root().unwrap();
+
+        let keys: Vec<_> = (0..50).map(get_key).collect();
+        let proof = tree.prove_many(&keys).unwrap();
+        // Proving each key on it's own sends the nodes close to the root many times, and
+        // also siblings that are other keys we are proving
+        let per_key: usize = keys
+            .iter()
+            .map(|key| tree.prove(*key).unwrap().compress().unwrap().nodes.len())
+            .sum();
+        assert!(proof.len() * 4 < per_key);
+
+        let mut leaves: Vec<_> = (0..50_u8)
+            .map(|i| (get_key(i), LeafNode::new(vec![i], i as u64)))
+            .collect();
+        // Order doesn't matter
+        leaves.reverse();
+        assert!(proof.verify(&leaves, &root).unwrap());
+
+        // A leaf with a different value
+        leaves[3].1 = LeafNode::new(vec![1], 1000);
+        assert!(!proof.verify(&leaves, &root).unwrap());
+
+        // Too many or too few leaves for this proof
+        leaves.pop();
+        assert!(proof.verify(&leaves, &root).is_err());
+        leaves.push((get_key(200), LeafNode::default()));
+        leaves.push((get_key(201), LeafNode::default()));
+        assert!(proof.verify(&leaves, &root).is_err());
+    }
+
+    #[test]
+    fn test_multi_proof_absent_keys() {
+        let mut tree = MSSMTree::new(MemoryDatabase::new());
+        tree.insert(get_key(0), vec![0], 10).unwrap();
+        let root = tree.root().unwrap();
+
+        let proof = tree.prove_many(&[get_key(0), get_key(1)]).unwrap();
+        let leaves = [
+            (get_key(0), LeafNode::new(vec![0], 10)),
+            (get_key(1), LeafNode::default()),
+        ];
+        assert!(proof.verify(&leaves, &root).unwrap());
+
+        // An empty multi-proof just proves the root
+        let proof = tree.prove_many(&[]).unwrap();
+        assert!(proof.verify(&[], &root).unwrap());
+    }
+
+    #[test]
+    fn test_duplicated_keys() {
+        let tree = MSSMTree::new(MemoryDatabase::new());
+        let proof = tree.prove_many(&[get_key(0), get_key(0)]).unwrap();
+        let leaves = [
+            (get_key(0), LeafNode::default()),
+            (get_key(0), LeafNode::default()),
+        ];
+        assert_eq!(
+            proof.root(&leaves).unwrap_err(),
+            Error::DuplicateKey(get_key(0))
+        );
+        assert!(proof.verify(&leaves[..1], &tree.root().unwrap()).unwrap());
+    }
+}
@@ -6,7 +6,7 @@
 
 use sha2::Digest;
 
-use crate::mssmt::node_hash::NodeHash;
+use crate::mssmt::{error::SumOverflow, node_hash::NodeHash, tree::empty_hashes};
 
 /// A trait that must be implemented by all nodes in the tree
 pub trait MSSMTNode {
@@ -21,13 +21,15 @@ pub trait MSSMTNode {
 pub enum Node {
     Leaf(LeafNode),
     Branch(DiskBranchNode),
+    Computed(ComputedNode),
+    Compacted(CompactedLeafNode),
+    /// A branch with it's children in memory, see
+    /// [TreeStore::fetch_branch_recursive](super::tree_backend::TreeStore::fetch_branch_recursive)
+    Subtree(Box<BranchNode>),
 }
 impl Default for Node {
     fn default() -> Self {
-        Node::Leaf(LeafNode {
-            data: vec![],
-            sum: 0,
-        })
+        Node::Leaf(LeafNode::default())
     }
 }
 #[derive(Debug, Clone)]
@@ -70,29 +72,111 @@ impl DiskBranchNode {
     }
 }
 impl BranchNode {
-    pub fn new(left: Node, right: Node) -> BranchNode {
-        let sum = left.node_sum() + right.node_sum();
+    /// Creates a new branch with `left` and `right` as children. Fails if the sum of both
+    /// children doesn't fit into a u64.
+    pub fn new(left: Node, right: Node) -> Result<BranchNode, SumOverflow> {
+        let sum = left
+            .node_sum()
+            .checked_add(right.node_sum())
+            .ok_or(SumOverflow)?;
         let hash = BranchNode::parent_hash(left.node_hash(), right.node_hash(), sum);
 
-        BranchNode {
+        Ok(BranchNode {
             sum,
             hash,
             left,
             right,
+        })
+    }
+    /// Builds a branch from a node we've read from the storage, and it's children. We trust
+    /// the stored sum, so this never fails.
+    pub(crate) fn from_disk(branch: &DiskBranchNode, left: Node, right: Node) -> BranchNode {
+        BranchNode {
+            sum: branch.sum,
+            hash: branch.node_hash(),
+            left,
+            right,
         }
     }
+    pub fn left(&self) -> &Node {
+        &self.left
+    }
+    pub fn right(&self) -> &Node {
+        &self.right
+    }
     fn parent_hash(left: NodeHash, right: NodeHash, sum: u64) -> NodeHash {
         let hash = sha2::Sha256::new()
-            .chain_update(&left)
-            .chain_update(&right)
+            .chain_update(left)
+            .chain_update(right)
             .chain_update(sum.to_be_bytes())
             .finalize();
         NodeHash::try_from(&*hash).unwrap()
     }
 }
-/// Leaves are nodes that contains the actual data being committed to, they sit at
-/// the last row and don't have any descendants.
+/// A [ComputedNode] is a node we only know the hash and sum of, like the nodes inside a
+/// proof we got from someone else. We can't tell whether it is a leaf or a branch, but
+/// that's all we need to hash it with it's sibling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComputedNode {
+    hash: NodeHash,
+    sum: u64,
+}
+
+impl ComputedNode {
+    pub fn new(hash: NodeHash, sum: u64) -> ComputedNode {
+        ComputedNode { hash, sum }
+    }
+}
+/// A [CompactedLeafNode] is a leaf that lives higher up in the tree. If a subtree has only
+/// one leaf, we can store the leaf at the subtree's root, instead of storing all branches
+/// down to the leaf. Since it isn't at the bottom, we must also keep the leaf's key, so we
+/// know where it would be in a full tree.
 #[derive(Debug, Clone)]
+pub struct CompactedLeafNode {
+    /// The depth where this leaf is stored
+    height: usize,
+    /// The leaf's position in the full tree
+    key: NodeHash,
+    leaf: LeafNode,
+    /// The hash of the subtree rooted at `height`, that only contains this leaf
+    hash: NodeHash,
+}
+
+impl CompactedLeafNode {
+    /// Creates a leaf stored at depth `height`. Every other leaf under it is empty, so we
+    /// compute it's hash by walking up from the bottom, next to the siblings in [empty_hashes].
+    pub fn new(height: usize, key: NodeHash, leaf: LeafNode) -> CompactedLeafNode {
+        let empty_hashes = empty_hashes();
+        let mut hash = leaf.node_hash();
+        for idx in (height..256).rev() {
+            let empty = empty_hashes[idx + 1];
+            hash = if key.bit_index(idx as u8) {
+                BranchNode::parent_hash(hash, empty, leaf.sum)
+            } else {
+                BranchNode::parent_hash(empty, hash, leaf.sum)
+            };
+        }
+        CompactedLeafNode {
+            height,
+            key,
+            leaf,
+            hash,
+        }
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn key(&self) -> &NodeHash {
+        &self.key
+    }
+    pub fn leaf(&self) -> &LeafNode {
+        &self.leaf
+    }
+}
+/// Leaves are nodes that contains the actual data being committed to, they sit at
+/// the last row and don't have any descendants. The default leaf is empty, and is what
+/// every empty position in the tree holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct LeafNode {
     data: Vec<u8>,
     sum: u64,
@@ -102,6 +186,10 @@ impl LeafNode {
     pub fn new(data: Vec<u8>, sum: u64) -> LeafNode {
         LeafNode { data, sum }
     }
+    /// The data this leaf commits to
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 }
 
 impl MSSMTNode for LeafNode {
@@ -120,8 +208,8 @@ impl MSSMTNode for LeafNode {
 impl MSSMTNode for DiskBranchNode {
     fn node_hash(&self) -> NodeHash {
         let hash = sha2::Sha256::new()
-            .chain_update(&self.left)
-            .chain_update(&self.right)
+            .chain_update(self.left)
+            .chain_update(self.right)
             .chain_update(self.sum.to_be_bytes())
             .finalize();
         NodeHash::try_from(&*hash).unwrap()
@@ -131,11 +219,32 @@ impl MSSMTNode for DiskBranchNode {
     }
 }
 
+impl MSSMTNode for ComputedNode {
+    fn node_hash(&self) -> NodeHash {
+        self.hash
+    }
+    fn node_sum(&self) -> u64 {
+        self.sum
+    }
+}
+
+impl MSSMTNode for CompactedLeafNode {
+    fn node_hash(&self) -> NodeHash {
+        self.hash
+    }
+    fn node_sum(&self) -> u64 {
+        self.leaf.sum
+    }
+}
+
 impl MSSMTNode for Node {
     fn node_hash(&self) -> NodeHash {
         match self {
             Node::Branch(inner) => inner.node_hash(),
             Node::Leaf(inner) => inner.node_hash(),
+            Node::Computed(inner) => inner.node_hash(),
+            Node::Compacted(inner) => inner.node_hash(),
+            Node::Subtree(inner) => inner.node_hash(),
         }
     }
 
@@ -143,6 +252,9 @@ impl MSSMTNode for Node {
         match self {
             Node::Branch(inner) => inner.node_sum(),
             Node::Leaf(inner) => inner.node_sum(),
+            Node::Computed(inner) => inner.node_sum(),
+            Node::Compacted(inner) => inner.node_sum(),
+            Node::Subtree(inner) => inner.node_sum(),
         }
     }
 }
@@ -150,8 +262,8 @@ impl MSSMTNode for Node {
 impl MSSMTNode for BranchNode {
     fn node_hash(&self) -> NodeHash {
         let hash = sha2::Sha256::new()
-            .chain_update(&self.left.node_hash())
-            .chain_update(&self.right.node_hash())
+            .chain_update(self.left.node_hash())
+            .chain_update(self.right.node_hash())
             .chain_update(self.sum.to_be_bytes())
             .finalize();
         NodeHash::try_from(&*hash).unwrap()
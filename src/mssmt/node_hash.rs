@@ -3,6 +3,7 @@
 //! like copy from a byte-array without checking length.
 
 use std::{
+    cmp::Ordering,
     fmt::{Debug, Display},
     ops::{Deref, DerefMut},
 };
@@ -10,7 +11,9 @@ use std::{
 #[cfg(test)]
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+use super::error::Error;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[cfg_attr(test, derive(Serialize, Deserialize))]
 pub struct NodeHash([u8; 32]);
 impl NodeHash {
@@ -20,7 +23,15 @@ impl NodeHash {
         let limb = i / 8;
         // a mask to fetch a bit inside this u8
         let mask = 1 << (i % 8);
-        return (self.0[limb as usize] & mask) == 0;
+        (self.0[limb as usize] & mask) == 0
+    }
+    /// Compares two keys by their position in the tree, from left to right. We descend
+    /// the tree looking at the least significant bit of each byte first, so this isn't the
+    /// same as comparing the bytes.
+    pub fn cmp_path(&self, other: &NodeHash) -> Ordering {
+        let this = self.0.iter().map(|byte| byte.reverse_bits());
+        let other = other.0.iter().map(|byte| byte.reverse_bits());
+        this.cmp(other)
     }
 }
 impl Debug for NodeHash {
@@ -39,11 +50,6 @@ impl DerefMut for NodeHash {
         &mut self.0
     }
 }
-impl Default for NodeHash {
-    fn default() -> Self {
-        NodeHash([0; 32])
-    }
-}
 impl From<[u8; 32]> for NodeHash {
     fn from(value: [u8; 32]) -> Self {
         NodeHash(value)
@@ -51,10 +57,10 @@ impl From<[u8; 32]> for NodeHash {
 }
 
 impl TryFrom<&[u8]> for NodeHash {
-    type Error = String;
+    type Error = Error;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         if value.len() != 32 {
-            return Err(format!("Invalid length {}", value.len()));
+            return Err(Error::InvalidHashLength(value.len()));
         }
         let mut hash = NodeHash([0; 32]);
         hash.0.clone_from_slice(value);
@@ -64,15 +70,15 @@ impl TryFrom<&[u8]> for NodeHash {
 impl<'a> TryFrom<&'a str> for NodeHash {
     fn try_from(value: &'a str) -> Result<Self, Self::Error> {
         if value.len() != 64 {
-            return Err("Invalid length".into());
+            return Err(Error::InvalidHashLength(value.len()));
         }
         let hex = hex::decode(value);
         match hex {
             Ok(data) => Ok(data.as_slice().try_into().expect("We already checked it")),
-            Err(e) => Err(format!("Invalid hex {e:?}")),
+            Err(e) => Err(Error::InvalidHex(e)),
         }
     }
-    type Error = String;
+    type Error = Error;
 }
 impl AsRef<[u8]> for NodeHash {
     fn as_ref(&self) -> &[u8] {
@@ -87,9 +93,9 @@ impl Display for NodeHash {
 
 #[cfg(test)]
 mod test {
-    use std::ops::Deref;
+    use std::{cmp::Ordering, ops::Deref};
 
-    use crate::mssmt::node_hash::NodeHash;
+    use crate::mssmt::{error::Error, node_hash::NodeHash};
 
     #[test]
     fn test_display() {
@@ -103,7 +109,7 @@ mod test {
     #[test]
     fn test_from_invalid_length_slice() {
         let res = NodeHash::try_from([0, 1, 2].as_slice());
-        assert_eq!(res, Err("Invalid length 3".into()));
+        assert_eq!(res, Err(Error::InvalidHashLength(3)));
     }
     #[test]
     fn test_deref() {
@@ -113,6 +119,22 @@ mod test {
         assert_eq!(&[0; 32], de);
     }
     #[test]
+    fn test_cmp_path() {
+        // Bit 0 is set, so this goes right on the first level
+        let mut right = [0; 32];
+        right[0] = 1;
+        // Bit 7 is set, so this only diverges from zero on the 8th level
+        let mut left = [0; 32];
+        left[0] = 0x80;
+        let zero = NodeHash::from([0; 32]);
+        let right = NodeHash::from(right);
+        let left = NodeHash::from(left);
+
+        assert_eq!(zero.cmp_path(&left), Ordering::Less);
+        assert_eq!(left.cmp_path(&right), Ordering::Less);
+        assert_eq!(right.cmp_path(&right), Ordering::Equal);
+    }
+    #[test]
     fn test_try_from_str_slice() {
         // echo Satoshi | sha256sum
         let hash = "fdd4d9893b23aa6cdb357e1606907c6909a1231595549e698f779a141d4534c7";
@@ -124,6 +146,6 @@ mod test {
         // Invalid 'k' at pos 3
         let hash = "fdk4d9893b23aa6cdb357e1606907c6909a1231595549e698f779a141d4534c7";
         let parsed = NodeHash::try_from(hash);
-        assert!(parsed.is_err());
+        assert!(matches!(parsed, Err(Error::InvalidHex(_))));
     }
 }
@@ -14,58 +14,195 @@
 //! can be used to reproduce the root, assuming the hash function is secure, the object must
 //! be in the original set.
 //!
+//! Most of the siblings in a proof are empty subtrees, and can be computed by anyone. So,
+//! before sending a proof over the wire, we [Proof::compress] it into a [CompressedProof],
+//! that only holds the non-empty siblings and a bitmap telling where the empty ones were.
+//! The encoding is the same as `mssmt.CompressedProof` from lightninglabs/taro, so proofs
+//! can be exchanged with Go nodes.
+//!
+//! Proofs have one sibling for each level in the tree, so their length depends on the
+//! tree's depth. That's the `DEPTH` parameter, that defaults to the 256 levels of a full
+//! tree. Only those can be encoded, since it's the only depth Go nodes know about.
 use super::{
+    error::Error,
     node::{BranchNode, LeafNode, MSSMTNode, Node},
     node_hash::NodeHash,
+    tree::{check_depth, empty_hashes_with_depth, empty_tree_with_depth},
 };
-/// The actual proof, just a list of nodes
+/// The actual proof, just a list of nodes. The first node is the root's child, and the last
+/// one is the leaf's sibling.
 #[derive(Debug)]
-pub struct Proof {
+pub struct Proof<const DEPTH: usize = 256> {
     nodes: Vec<Node>,
 }
 impl Proof {
     pub fn new(nodes: Vec<Node>) -> Proof {
+        Proof::with_depth(nodes)
+    }
+}
+impl<const DEPTH: usize> Proof<DEPTH> {
+    /// Like [Proof::new], for a tree with `DEPTH` levels. Trees can't be deeper than 256
+    /// levels, so neither can proofs:
+    /// ```compile_fail,E0080
+    ///    use rust_taro::mssmt::proof::Proof;
+    ///
+    ///    let proof = Proof::<257>::with_depth(vec![]);
+    /// ```
+    pub fn with_depth(nodes: Vec<Node>) -> Proof<DEPTH> {
+        check_depth::<DEPTH>();
         Proof { nodes }
     }
+    /// Returns the siblings in this proof, from the top of the tree to the bottom
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+    /// Fails with [Error::ShortProof] or [Error::LongProof] unless we have one node for
+    /// each level
+    fn check_len(&self) -> Result<(), Error> {
+        if self.nodes.len() < DEPTH {
+            return Err(Error::ShortProof(self.nodes.len()));
+        }
+        if self.nodes.len() > DEPTH {
+            return Err(Error::LongProof(self.nodes.len()));
+        }
+        Ok(())
+    }
+    /// Compresses this proof by replacing all empty siblings with a bit in a bitmap. Fails
+    /// if this proof doesn't have one node for each level.
+    pub fn compress(&self) -> Result<CompressedProof<DEPTH>, Error> {
+        self.check_len()?;
+        let empty_hashes = empty_hashes_with_depth::<DEPTH>();
+        let mut bits = Vec::with_capacity(self.nodes.len());
+        let mut nodes = Vec::new();
+        // Our nodes start at the root, but compressed proofs start at the leaf. So we walk
+        // backwards here. The i-th node is a child of level i, and lives on level i + 1.
+        for (idx, node) in self.nodes.iter().enumerate().rev() {
+            if node.node_hash() == empty_hashes[idx + 1] {
+                bits.push(true);
+            } else {
+                bits.push(false);
+                nodes.push(node.clone());
+            }
+        }
+        Ok(CompressedProof { bits, nodes })
+    }
+}
+/// A [Proof] without it's empty nodes. This is the format used to send proofs to others.
+#[derive(Debug, Clone)]
+pub struct CompressedProof<const DEPTH: usize = 256> {
+    /// One bit for each level, starting from the leaf. If a bit is set, the sibling at this
+    /// level is an empty subtree, and it isn't in `nodes`.
+    pub(super) bits: Vec<bool>,
+    /// All non-empty siblings, starting from the leaf.
+    pub(super) nodes: Vec<Node>,
+}
+impl<const DEPTH: usize> CompressedProof<DEPTH> {
+    /// Rebuilds the full proof, filling in the empty siblings
+    pub fn decompress(&self) -> Result<Proof<DEPTH>, Error> {
+        if self.bits.len() < DEPTH {
+            return Err(Error::ShortProof(self.bits.len()));
+        }
+        if self.bits.len() > DEPTH {
+            return Err(Error::LongProof(self.bits.len()));
+        }
+        let non_empty = self.bits.iter().filter(|bit| !**bit).count();
+        if non_empty != self.nodes.len() {
+            return Err(Error::ProofNodeMismatch {
+                expected: non_empty,
+                found: self.nodes.len(),
+            });
+        }
+        let empty_tree = empty_tree_with_depth::<DEPTH>();
+        let mut nodes = self.nodes.iter();
+        let mut proof = Vec::with_capacity(DEPTH);
+        for (idx, empty) in self.bits.iter().enumerate() {
+            // Bits start at the leaf, while the empty tree starts at the root
+            if *empty {
+                proof.push(empty_tree[DEPTH - idx].clone());
+            } else {
+                let node = nodes.next().expect("We've checked the number of nodes");
+                proof.push(node.clone());
+            }
+        }
+        // Our proofs start at the root
+        proof.reverse();
+        Ok(Proof::with_depth(proof))
+    }
 }
-/// Objects that can produce proofs, like a full tree
-pub trait Provable {
+/// Objects that can produce proofs, like a full tree. `DEPTH` is the depth of the tree
+/// being proven.
+pub trait Provable<const DEPTH: usize = 256> {
     type Error;
-    fn prove(&self, key: NodeHash) -> Result<Proof, Self::Error>;
+    fn prove(&self, key: NodeHash) -> Result<Proof<DEPTH>, Self::Error>;
+    /// Proves that there's nothing at `key`. Returns [None] if `key` is in the tree, since
+    /// we can't prove it's absence.
+    fn prove_absence(&self, key: NodeHash) -> Result<Option<Proof<DEPTH>>, Self::Error>;
 }
 /// Things that can be verified, like Proofs
-pub trait Verifiable {
+pub trait Verifiable: Sized {
     type Error;
-    fn verify(self, target_leaf: &LeafNode, key: &NodeHash) -> Result<NodeHash, Self::Error>;
+    /// Hashes `target_leaf` all the way up to the root, returning the root's hash and sum
+    fn root(self, target_leaf: &LeafNode, key: &NodeHash) -> Result<(NodeHash, u64), Self::Error>;
+    /// Like [Verifiable::root], but only returns the root hash
+    fn verify(self, target_leaf: &LeafNode, key: &NodeHash) -> Result<NodeHash, Self::Error> {
+        Ok(self.root(target_leaf, key)?.0)
+    }
+    /// Checks whether `target_leaf` is at `key` in the tree with `expected_root`. Both the
+    /// root hash and sum must match, so a proof can't be used to inflate the root's sum.
+    fn verify_root(
+        self,
+        target_leaf: &LeafNode,
+        key: &NodeHash,
+        expected_root: &impl MSSMTNode,
+    ) -> Result<bool, Self::Error> {
+        let (hash, sum) = self.root(target_leaf, key)?;
+        Ok(hash == expected_root.node_hash() && sum == expected_root.node_sum())
+    }
+    /// Checks whether `key` is empty in the tree with `expected_root`. Empty positions hold
+    /// an empty leaf, the last element of the empty tree, so this is the same as verifying
+    /// the inclusion of an empty leaf.
+    fn verify_non_inclusion(
+        self,
+        key: &NodeHash,
+        expected_root: &NodeHash,
+    ) -> Result<bool, Self::Error> {
+        let root = self.verify(&LeafNode::default(), key)?;
+        Ok(root == *expected_root)
+    }
 }
 
-impl Verifiable for Proof {
-    type Error = String;
-    fn verify(mut self, target_leaf: &LeafNode, key: &NodeHash) -> Result<NodeHash, Self::Error> {
+impl<const DEPTH: usize> Verifiable for Proof<DEPTH> {
+    type Error = Error;
+    fn root(self, target_leaf: &LeafNode, key: &NodeHash) -> Result<(NodeHash, u64), Self::Error> {
+        // Bits past 255 don't exist, so we can't hash deeper proofs
+        check_depth::<DEPTH>();
+        self.check_len()?;
         let mut current_node = Node::Leaf(target_leaf.to_owned());
 
-        for idx in (0..=255).rev() {
-            let node = self.nodes.pop().unwrap();
-            current_node = if key.bit_index(idx) {
-                Node::Branch(BranchNode::new(current_node, node).into())
+        for (idx, node) in self.nodes.into_iter().enumerate().rev() {
+            // A malicious proof may have sums that overflow the parent's sum
+            current_node = if key.bit_index(idx as u8) {
+                Node::Branch(BranchNode::new(current_node, node)?.into())
             } else {
-                Node::Branch(BranchNode::new(node, current_node).into())
+                Node::Branch(BranchNode::new(node, current_node)?.into())
             }
         }
-        Ok(current_node.node_hash())
+        Ok((current_node.node_hash(), current_node.node_sum()))
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::mssmt::{
+        error::Error,
         memory_db::MemoryDatabase,
-        node::LeafNode,
+        node::{ComputedNode, LeafNode, MSSMTNode, Node},
         node_hash::NodeHash,
         tree::{MSSMTree, Tree},
     };
 
-    use super::{Provable, Verifiable};
+    use super::{CompressedProof, Proof, Provable, Verifiable};
+    use crate::mssmt::encoding::{Decodable, Encodable};
 
     #[test]
     fn test_proof() {
@@ -86,4 +223,105 @@ mod test {
             root
         )
     }
+    #[test]
+    fn test_compress_empty_proof() {
+        let tree = MSSMTree::new(MemoryDatabase::new());
+        let proof = tree.prove(NodeHash::from([0; 32])).unwrap();
+        let compressed = proof.compress().unwrap();
+        assert!(compressed.nodes.is_empty());
+        assert!(compressed.bits.iter().all(|bit| *bit));
+
+        let mut encoded = vec![];
+        compressed.encode(&mut encoded).unwrap();
+        let mut expected = vec![0, 0];
+        expected.extend([0xff; 32]);
+        assert_eq!(encoded, expected);
+    }
+    #[test]
+    fn test_compressed_proof_round_trip() {
+        let mut tree = MSSMTree::new(MemoryDatabase::new());
+        tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![2], 20).unwrap();
+        tree.insert(NodeHash::from([2; 32]), vec![3], 30).unwrap();
+
+        let proof = tree.prove(NodeHash::from([0; 32])).unwrap();
+        let compressed = proof.compress().unwrap();
+        assert!(!compressed.nodes.is_empty());
+
+        let mut encoded = vec![];
+        compressed.encode(&mut encoded).unwrap();
+        assert_eq!(encoded.len(), 2 + compressed.nodes.len() * 40 + 32);
+
+        let decoded = CompressedProof::decode(&mut encoded.as_slice()).unwrap();
+        let decompressed = decoded.decompress().unwrap();
+        for (original, decompressed) in proof.nodes().iter().zip(decompressed.nodes()) {
+            assert_eq!(original.node_hash(), decompressed.node_hash());
+            assert_eq!(original.node_sum(), decompressed.node_sum());
+        }
+
+        let leaf = LeafNode::new(vec![1], 10);
+        let key = NodeHash::from([0; 32]);
+        assert_eq!(
+            proof.verify(&leaf, &key).unwrap(),
+            decompressed.verify(&leaf, &key).unwrap()
+        );
+    }
+    #[test]
+    fn test_decompress_missing_nodes() {
+        let compressed: CompressedProof = CompressedProof {
+            bits: vec![false; 256],
+            nodes: vec![],
+        };
+        assert_eq!(
+            compressed.decompress().unwrap_err(),
+            Error::ProofNodeMismatch {
+                expected: 256,
+                found: 0
+            }
+        );
+        assert!(CompressedProof::decode(&mut [0_u8, 1].as_slice()).is_err());
+    }
+    #[test]
+    fn test_invalid_proof_length() {
+        let leaf = LeafNode::new(vec![1], 1);
+        let key = NodeHash::from([0; 32]);
+
+        let proof = Proof::new(vec![Node::default(); 10]);
+        assert_eq!(proof.verify(&leaf, &key), Err(Error::ShortProof(10)));
+
+        let proof = Proof::new(vec![Node::default(); 257]);
+        assert_eq!(proof.compress().unwrap_err(), Error::LongProof(257));
+        assert!(proof.encode(&mut vec![]).is_err());
+        assert_eq!(proof.verify(&leaf, &key), Err(Error::LongProof(257)));
+    }
+    #[test]
+    fn test_shallow_proof() {
+        let mut tree = MSSMTree::<_, 16>::with_depth(MemoryDatabase::new(), "shallow");
+        tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![2], 20).unwrap();
+
+        let leaf = LeafNode::new(vec![2], 20);
+        let key = NodeHash::from([1; 32]);
+        let proof = tree.prove(key).unwrap();
+        let compressed = proof.compress().unwrap();
+        assert_eq!(compressed.bits.len(), 16);
+        assert_eq!(compressed.nodes.len(), 1);
+
+        let proof = compressed.decompress().unwrap();
+        assert_eq!(proof.verify(&leaf, &key).unwrap(), tree.root_hash());
+
+        // Proofs for full trees are too long
+        let proof = Proof::<16>::with_depth(vec![Node::default(); 256]);
+        assert_eq!(proof.verify(&leaf, &key), Err(Error::LongProof(256)));
+    }
+    #[test]
+    fn test_proof_sum_overflow() {
+        let leaf = LeafNode::new(vec![1], 1);
+        let key = NodeHash::from([0; 32]);
+        let mut nodes = vec![Node::default(); 256];
+        nodes[100] = Node::Computed(ComputedNode::new(NodeHash::from([1; 32]), u64::MAX));
+
+        let proof = Proof::new(nodes);
+        assert_eq!(proof.verify(&leaf, &key), Err(Error::SumOverflow));
+    }
 }
@@ -0,0 +1,242 @@
+//! Nodes are keyed by their hash, so two identical leaves, or two identical subtrees, are
+//! stored only once. Trees delete the nodes they are replacing, so deleting one copy would
+//! also remove the other, leaving a path that points to a missing node.
+//!
+//! [RefCountedStore] wraps another store and counts how many times each node was inserted.
+//! A delete only reaches the inner store when the last reference to a node goes away. Each
+//! namespace has it's own counts, like it has it's own nodes.
+//!
+//! Counts are saved in the inner store, with [Op::SetRefCount], in the same batch as the
+//! nodes they count. So they survive restarts, and a store can be wrapped again after being
+//! reopened. Nodes written before the store was wrapped have no count, and are never deleted.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Mutex, PoisonError},
+};
+
+use super::{
+    node::{BranchNode, CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode},
+    node_hash::NodeHash,
+    tree_backend::{Op, TreeStore},
+};
+
+#[derive(Debug)]
+pub struct RefCountedStore<Persistence: TreeStore> {
+    inner: Persistence,
+    /// Held while updating counts, so two batches can't both read a count and then write
+    /// their own increment over the other's
+    writing: Mutex<()>,
+}
+
+impl<Persistence: TreeStore> RefCountedStore<Persistence> {
+    pub fn new(inner: Persistence) -> RefCountedStore<Persistence> {
+        RefCountedStore {
+            inner,
+            writing: Mutex::new(()),
+        }
+    }
+    /// The store holding both the nodes and their counts. Writes made straight to it aren't
+    /// counted, so use it for reads only.
+    pub fn inner(&self) -> &Persistence {
+        &self.inner
+    }
+    /// How many references `hash` has inside `namespace`, zero if it isn't stored
+    pub fn ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Persistence::Error> {
+        self.inner.get_ref_count(namespace, hash)
+    }
+}
+
+impl<Persistence: TreeStore> TreeStore for RefCountedStore<Persistence> {
+    type Error = Persistence::Error;
+
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        // This lock guards no data, it only keeps writers apart. Counts and nodes reach the
+        // inner store in one batch, so a writer that panicked wrote either all or none.
+        let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        // The counts we touch, that are written together with the nodes
+        let mut changed: HashMap<NodeHash, u64> = HashMap::new();
+        let mut inner_ops = Vec::with_capacity(ops.len());
+        for op in ops {
+            let hash = match &op {
+                // Those aren't nodes, so there's nothing to count
+                Op::SetRoot(..) | Op::SetRefCount(..) => {
+                    inner_ops.push(op);
+                    continue;
+                }
+                Op::InsertBranch(branch) => branch.node_hash(),
+                Op::InsertLeaf(leaf) => leaf.node_hash(),
+                Op::InsertCompactedLeaf(leaf) => leaf.node_hash(),
+                Op::DeleteBranch(hash) | Op::DeleteLeaf(hash) | Op::DeleteCompactedLeaf(hash) => {
+                    *hash
+                }
+            };
+            let count = match changed.entry(hash) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.inner.get_ref_count(namespace, &hash)?),
+            };
+            match op {
+                // A new node should be written, otherwise we only add a reference
+                Op::InsertBranch(_) | Op::InsertLeaf(_) | Op::InsertCompactedLeaf(_) => {
+                    *count += 1;
+                    if *count == 1 {
+                        inner_ops.push(op);
+                    }
+                }
+                // Nodes we never counted are left alone
+                _ if *count == 0 => {}
+                // Only the last reference actually deletes the node
+                _ => {
+                    *count -= 1;
+                    if *count == 0 {
+                        inner_ops.push(op);
+                    }
+                }
+            }
+        }
+        inner_ops.extend(
+            changed
+                .into_iter()
+                .map(|(hash, count)| Op::SetRefCount(hash, count)),
+        );
+        if !inner_ops.is_empty() {
+            self.inner.apply_batch(namespace, inner_ops)?;
+        }
+        Ok(())
+    }
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        self.inner.get_root(namespace)
+    }
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        self.inner.get_ref_count(namespace, hash)
+    }
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertBranch(branch)])
+    }
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertLeaf(leaf)])
+    }
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteBranch(hash)])
+    }
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteLeaf(hash)])
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        self.inner.fetch_branch(namespace, hash)
+    }
+    fn fetch_branch_recursive(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+        max_depth: usize,
+    ) -> Result<Option<BranchNode>, Self::Error> {
+        self.inner
+            .fetch_branch_recursive(namespace, hash, max_depth)
+    }
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        self.inner.fetch_leaf(namespace, hash)
+    }
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::InsertCompactedLeaf(leaf)])
+    }
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::DeleteCompactedLeaf(hash)])
+    }
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        self.inner.fetch_compacted_leaf(namespace, hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        error::Error,
+        memory_db::MemoryDatabase,
+        node::{LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        tree::{MSSMTree, Tree},
+        tree_backend::DEFAULT_NAMESPACE,
+    };
+
+    use super::RefCountedStore;
+
+    #[test]
+    fn test_shared_leaves() {
+        let mut tree = MSSMTree::new(RefCountedStore::new(MemoryDatabase::new()));
+        // Both leaves have the same hash, so they share the same stored node
+        tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![1], 10).unwrap();
+        let leaf = LeafNode::new(vec![1], 10);
+        assert_eq!(
+            tree.database()
+                .ref_count(DEFAULT_NAMESPACE, &leaf.node_hash())
+                .unwrap(),
+            2
+        );
+
+        tree.delete(NodeHash::from([0; 32])).unwrap();
+        assert_eq!(
+            tree.database()
+                .ref_count(DEFAULT_NAMESPACE, &leaf.node_hash())
+                .unwrap(),
+            1
+        );
+        let found = tree.lookup(NodeHash::from([1; 32])).unwrap().unwrap();
+        assert_eq!(found.node_hash(), leaf.node_hash());
+        tree.check_consistency().unwrap();
+
+        tree.delete(NodeHash::from([1; 32])).unwrap();
+        assert_eq!(
+            tree.database()
+                .ref_count(DEFAULT_NAMESPACE, &leaf.node_hash())
+                .unwrap(),
+            0
+        );
+        assert!(tree.database().inner().is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_without_ref_count() {
+        let mut tree = MSSMTree::new(MemoryDatabase::new());
+        tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![1], 10).unwrap();
+        tree.delete(NodeHash::from([0; 32])).unwrap();
+
+        // Both paths end in the same branches near the bottom, and they were deleted with
+        // the first key
+        let res = tree.check_consistency();
+        assert!(matches!(res, Err(Error::MissingNode(_))));
+        let res = tree.lookup(NodeHash::from([1; 32]));
+        assert!(matches!(res, Err(Error::MissingNode(_))));
+    }
+
+    #[test]
+    fn test_many_shared_leaves() {
+        let mut tree = MSSMTree::new(RefCountedStore::new(MemoryDatabase::new()));
+        for i in 0..50_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i % 3], 1)
+                .unwrap();
+        }
+        for i in (0..50_u8).step_by(2) {
+            tree.delete(NodeHash::from([i; 32])).unwrap();
+            tree.check_consistency().unwrap();
+        }
+        assert_eq!(tree.root().unwrap().node_sum(), 25);
+        for i in (1..50_u8).step_by(2) {
+            let leaf = tree.lookup(NodeHash::from([i; 32])).unwrap().unwrap();
+            assert_eq!(leaf.data(), &vec![i % 3]);
+        }
+    }
+}
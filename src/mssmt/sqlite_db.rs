@@ -0,0 +1,426 @@
+//! A persistent backend for trees, on top of SQLite. The schema follows the one used by the
+//! Go taro daemon for it's mssmt tables, but with one table for each kind of node, so we
+//! never need to guess what a row holds.
+//!
+//! One database file can hold many trees. Each tree lives in it's own namespace, and every
+//! row is keyed by `(hash_key, namespace)`, so identical nodes in different trees are kept
+//! apart. Saved roots live in `mssmt_roots`, keyed by namespace, and reference counts live
+//! in `mssmt_ref_counts`, keyed like nodes.
+//!
+//! Every batch of writes is atomic, see [TreeStore::apply_batch]. Each change to a tree is
+//! written as one batch, and so is a whole [MSSMTree::insert_batch], so a tree never sees
+//! half of an update.
+//!
+//! [MSSMTree::insert_batch]: super::tree::MSSMTree::insert_batch
+//!
+//! # Usage:
+//! ```
+//!    use rust_taro::mssmt::{sqlite_db::SqliteTreeStore, node_hash::NodeHash};
+//!    use rust_taro::mssmt::tree::{MSSMTree, Tree};
+//!
+//!    let storage = SqliteTreeStore::open_in_memory().unwrap();
+//!    let mut tree = MSSMTree::with_namespace(&storage, "assets");
+//!
+//!    tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+//!
+//!    assert!(tree.lookup(NodeHash::from([0; 32])).unwrap().is_some());
+//!```
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{
+    node::{CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode},
+    node_hash::NodeHash,
+    tree_backend::{Op, TreeStore},
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS mssmt_branches (
+    hash_key BLOB NOT NULL,
+    namespace VARCHAR NOT NULL,
+    l_hash_key BLOB NOT NULL,
+    r_hash_key BLOB NOT NULL,
+    sum BIGINT NOT NULL,
+    PRIMARY KEY (hash_key, namespace)
+);
+CREATE TABLE IF NOT EXISTS mssmt_leaves (
+    hash_key BLOB NOT NULL,
+    namespace VARCHAR NOT NULL,
+    value BLOB NOT NULL,
+    sum BIGINT NOT NULL,
+    PRIMARY KEY (hash_key, namespace)
+);
+CREATE TABLE IF NOT EXISTS mssmt_compacted_leaves (
+    hash_key BLOB NOT NULL,
+    namespace VARCHAR NOT NULL,
+    height INTEGER NOT NULL,
+    leaf_key BLOB NOT NULL,
+    value BLOB NOT NULL,
+    sum BIGINT NOT NULL,
+    PRIMARY KEY (hash_key, namespace)
+);
+CREATE TABLE IF NOT EXISTS mssmt_roots (
+    namespace VARCHAR NOT NULL PRIMARY KEY,
+    root_hash BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS mssmt_ref_counts (
+    hash_key BLOB NOT NULL,
+    namespace VARCHAR NOT NULL,
+    ref_count BIGINT NOT NULL,
+    PRIMARY KEY (hash_key, namespace)
+);
+";
+
+#[derive(Debug, Clone)]
+pub struct SqliteTreeStore {
+    /// Every handle to the same database shares this connection
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteTreeStore {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SqliteStoreError> {
+        Self::from_connection(Connection::open(path)?)
+    }
+    /// Creates a new database that only lives in memory, mostly useful for tests
+    pub fn open_in_memory() -> Result<Self, SqliteStoreError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+    /// Uses an already open connection, creating our tables if they don't exist yet
+    pub fn from_connection(connection: Connection) -> Result<Self, SqliteStoreError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteTreeStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, SqliteStoreError> {
+        Ok(self.connection.lock()?)
+    }
+    /// Runs a single write inside `namespace` on `connection`, that must be ours and already
+    /// locked
+    fn apply(connection: &Connection, namespace: &str, op: Op) -> Result<(), rusqlite::Error> {
+        match op {
+            Op::InsertBranch(branch) => connection.execute(
+                "INSERT OR IGNORE INTO mssmt_branches (hash_key, namespace, l_hash_key, r_hash_key, sum)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    *branch.node_hash(),
+                    namespace,
+                    **branch.l_child(),
+                    **branch.r_child(),
+                    // SQLite only has signed integers, big sums wrap around and back
+                    branch.node_sum() as i64,
+                ],
+            ),
+            Op::InsertLeaf(leaf) => connection.execute(
+                "INSERT OR IGNORE INTO mssmt_leaves (hash_key, namespace, value, sum)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    *leaf.node_hash(),
+                    namespace,
+                    leaf.data(),
+                    leaf.node_sum() as i64,
+                ],
+            ),
+            Op::InsertCompactedLeaf(leaf) => connection.execute(
+                "INSERT OR IGNORE INTO mssmt_compacted_leaves
+                 (hash_key, namespace, height, leaf_key, value, sum)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    *leaf.node_hash(),
+                    namespace,
+                    leaf.height() as i64,
+                    **leaf.key(),
+                    leaf.leaf().data(),
+                    leaf.node_sum() as i64,
+                ],
+            ),
+            Op::DeleteBranch(hash) => connection.execute(
+                "DELETE FROM mssmt_branches WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+            ),
+            Op::DeleteLeaf(hash) => connection.execute(
+                "DELETE FROM mssmt_leaves WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+            ),
+            Op::DeleteCompactedLeaf(hash) => connection.execute(
+                "DELETE FROM mssmt_compacted_leaves WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+            ),
+            Op::SetRoot(root) => connection.execute(
+                "INSERT OR REPLACE INTO mssmt_roots (namespace, root_hash) VALUES (?1, ?2)",
+                params![namespace, *root],
+            ),
+            Op::SetRefCount(hash, 0) => connection.execute(
+                "DELETE FROM mssmt_ref_counts WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+            ),
+            Op::SetRefCount(hash, count) => connection.execute(
+                "INSERT OR REPLACE INTO mssmt_ref_counts (hash_key, namespace, ref_count)
+                 VALUES (?1, ?2, ?3)",
+                params![*hash, namespace, count as i64],
+            ),
+        }?;
+        Ok(())
+    }
+}
+
+impl TreeStore for SqliteTreeStore {
+    type Error = SqliteStoreError;
+
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        let mut connection = self.connection()?;
+        // Dropping the transaction without committing rolls back whatever was written
+        let transaction = connection.transaction()?;
+        for op in ops {
+            Self::apply(&transaction, namespace, op)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        let root = self
+            .connection()?
+            .query_row(
+                "SELECT root_hash FROM mssmt_roots WHERE namespace = ?1",
+                params![namespace],
+                |row| row.get::<_, [u8; 32]>(0),
+            )
+            .optional()?;
+        Ok(root.map(NodeHash::from))
+    }
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        let count = self
+            .connection()?
+            .query_row(
+                "SELECT ref_count FROM mssmt_ref_counts WHERE hash_key = ?1 AND namespace = ?2",
+                params![**hash, namespace],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        Ok(count.unwrap_or(0) as u64)
+    }
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::InsertBranch(branch),
+        )?)
+    }
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::InsertLeaf(leaf),
+        )?)
+    }
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::DeleteBranch(hash),
+        )?)
+    }
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::DeleteLeaf(hash),
+        )?)
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        let branch = self
+            .connection()?
+            .query_row(
+                "SELECT l_hash_key, r_hash_key, sum FROM mssmt_branches
+                 WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+                |row| {
+                    let left: [u8; 32] = row.get(0)?;
+                    let right: [u8; 32] = row.get(1)?;
+                    let sum: i64 = row.get(2)?;
+                    Ok(DiskBranchNode::new(sum as u64, left.into(), right.into()))
+                },
+            )
+            .optional()?;
+        Ok(branch)
+    }
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        let leaf = self
+            .connection()?
+            .query_row(
+                "SELECT value, sum FROM mssmt_leaves WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+                |row| {
+                    let sum: i64 = row.get(1)?;
+                    Ok(LeafNode::new(row.get(0)?, sum as u64))
+                },
+            )
+            .optional()?;
+        Ok(leaf)
+    }
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::InsertCompactedLeaf(leaf),
+        )?)
+    }
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        Ok(Self::apply(
+            &*self.connection()?,
+            namespace,
+            Op::DeleteCompactedLeaf(hash),
+        )?)
+    }
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        let leaf = self
+            .connection()?
+            .query_row(
+                "SELECT height, leaf_key, value, sum FROM mssmt_compacted_leaves
+                 WHERE hash_key = ?1 AND namespace = ?2",
+                params![*hash, namespace],
+                |row| {
+                    let height: i64 = row.get(0)?;
+                    let key: [u8; 32] = row.get(1)?;
+                    let sum: i64 = row.get(3)?;
+                    let leaf = LeafNode::new(row.get(2)?, sum as u64);
+                    Ok(CompactedLeafNode::new(height as usize, key.into(), leaf))
+                },
+            )
+            .optional()?;
+        Ok(leaf)
+    }
+}
+
+#[derive(Debug)]
+pub enum SqliteStoreError {
+    PoisonedLock,
+    Sqlite(rusqlite::Error),
+}
+impl<T> From<PoisonError<T>> for SqliteStoreError {
+    fn from(_: PoisonError<T>) -> Self {
+        Self::PoisonedLock
+    }
+}
+impl From<rusqlite::Error> for SqliteStoreError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mssmt::{
+        node::{CompactedLeafNode, LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        ref_count::RefCountedStore,
+        tree::{MSSMTree, Tree},
+        tree_backend::{Op, TreeStore, DEFAULT_NAMESPACE},
+    };
+
+    use super::SqliteTreeStore;
+
+    #[test]
+    fn test_store() {
+        let store = SqliteTreeStore::open_in_memory().unwrap();
+        crate::mssmt::tree_backend::test::test_store(store);
+    }
+
+    #[test]
+    fn test_atomic_batch() {
+        let store = SqliteTreeStore::open_in_memory().unwrap();
+        let leaf = LeafNode::new(vec![1], 1);
+        let compacted = CompactedLeafNode::new(1, [0; 32].into(), leaf.clone());
+        // Makes the second write fail
+        store
+            .connection()
+            .unwrap()
+            .execute_batch("DROP TABLE mssmt_compacted_leaves")
+            .unwrap();
+
+        let res = store.apply_batch(
+            DEFAULT_NAMESPACE,
+            vec![
+                Op::InsertLeaf(leaf.clone()),
+                Op::InsertCompactedLeaf(compacted),
+            ],
+        );
+        assert!(res.is_err());
+        assert!(store
+            .fetch_leaf(DEFAULT_NAMESPACE, leaf.node_hash())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("mssmt-{}.sqlite", std::process::id()));
+        let root = {
+            let store = SqliteTreeStore::open(&path).unwrap();
+            let mut tree = MSSMTree::open(store, "assets").unwrap();
+            tree.insert(NodeHash::from([0; 32]), vec![0], 1).unwrap();
+            tree.root_hash()
+        };
+        let store = SqliteTreeStore::open(&path).unwrap();
+        let tree = MSSMTree::open(store, "assets").unwrap();
+        assert_eq!(tree.root_hash(), root);
+        let leaf = tree.lookup(NodeHash::from([0; 32])).unwrap().unwrap();
+        assert_eq!(leaf.node_sum(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_ref_count_persistence() {
+        let path = std::env::temp_dir().join(format!("mssmt-rc-{}.sqlite", std::process::id()));
+        let open = || {
+            let store = RefCountedStore::new(SqliteTreeStore::open(&path).unwrap());
+            MSSMTree::open(store, "assets").unwrap()
+        };
+        let leaf = LeafNode::new(vec![1], 10);
+        {
+            let mut tree = open();
+            // Both keys share the same leaf
+            tree.insert(NodeHash::from([0; 32]), vec![1], 10).unwrap();
+            tree.insert(NodeHash::from([1; 32]), vec![1], 10).unwrap();
+        }
+        let mut tree = open();
+        let count = tree.database().ref_count("assets", &leaf.node_hash());
+        assert_eq!(count.unwrap(), 2);
+
+        // Adding and removing another copy keeps the ones we had before reopening
+        tree.insert(NodeHash::from([2; 32]), vec![1], 10).unwrap();
+        tree.delete(NodeHash::from([2; 32])).unwrap();
+        tree.delete(NodeHash::from([0; 32])).unwrap();
+        tree.check_consistency().unwrap();
+        let found = tree.lookup(NodeHash::from([1; 32])).unwrap().unwrap();
+        assert_eq!(found.node_hash(), leaf.node_hash());
+
+        drop(tree);
+        let mut tree = open();
+        tree.delete(NodeHash::from([1; 32])).unwrap();
+        let store = tree.database().inner();
+        assert!(store
+            .fetch_leaf("assets", leaf.node_hash())
+            .unwrap()
+            .is_none());
+        assert_eq!(store.get_ref_count("assets", &leaf.node_hash()).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,8 +1,16 @@
+use std::{
+    ops::{RangeBounds, RangeFull},
+    sync::OnceLock,
+};
+
 use super::{
-    node::{DiskBranchNode, LeafNode, MSSMTNode, Node},
+    error::{Error, SumOverflow},
+    iter::Leaves,
+    multi_proof::MultiProof,
+    node::{CompactedLeafNode, ComputedNode, DiskBranchNode, LeafNode, MSSMTNode, Node},
     node_hash::NodeHash,
     proof::{Proof, Provable},
-    tree_backend::TreeStore,
+    tree_backend::{Op, TreeStore, DEFAULT_NAMESPACE},
 };
 
 /// Defines all operations in a full tree
@@ -17,191 +25,707 @@ pub trait Tree<E> {
     fn lookup(&self, key: NodeHash) -> Result<Option<LeafNode>, E>;
 }
 
+/// The nodes of a tree without any leaves, and their hashes. This is the same for every
+/// tree, so it's computed once, the first time someone needs it.
+static EMPTY_TREE: OnceLock<(Vec<Node>, Vec<NodeHash>)> = OnceLock::new();
+
+fn compute_empty_tree() -> (Vec<Node>, Vec<NodeHash>) {
+    let mut empty_tree: Vec<Node> = Vec::with_capacity(257);
+    let mut node = Node::default();
+    empty_tree.push(node.clone());
+    // Creates the empty tree
+    for _ in 0..=255 {
+        let branch = Node::Branch(DiskBranchNode::new(0, node.node_hash(), node.node_hash()));
+        node = branch;
+        empty_tree.push(node.clone());
+    }
+    // We build it in reverse order, from leaf to root. But in a tree, index 0 is the root
+    // so we reverse that here.
+    empty_tree.reverse();
+    let hashes = empty_tree.iter().map(|node| node.node_hash()).collect();
+    (empty_tree, hashes)
+}
+
+/// The nodes of a tree without any leaves. Index 0 is the root and index 256 is an empty
+/// leaf, so `empty_tree()[i]` is what an empty subtree looks like at depth `i`.
+pub fn empty_tree() -> &'static [Node] {
+    &EMPTY_TREE.get_or_init(compute_empty_tree).0
+}
+
+/// The hashes of [empty_tree], so we can check if a subtree is empty without hashing
+pub fn empty_hashes() -> &'static [NodeHash] {
+    &EMPTY_TREE.get_or_init(compute_empty_tree).1
+}
+
+/// Fails to compile if `DEPTH` isn't a valid depth. Our keys have 256 bits, so trees can't
+/// be any deeper than that.
+pub(super) const fn check_depth<const DEPTH: usize>() {
+    const { assert!(DEPTH > 0 && DEPTH <= 256, "DEPTH must be in 1..=256") };
+}
+
+/// Like [empty_tree], for a tree with `DEPTH` levels. A shallow tree looks just like the
+/// bottom of a full one.
+pub(super) fn empty_tree_with_depth<const DEPTH: usize>() -> &'static [Node] {
+    check_depth::<DEPTH>();
+    &empty_tree()[256 - DEPTH..]
+}
+
+/// Like [empty_hashes], for a tree with `DEPTH` levels
+pub(super) fn empty_hashes_with_depth<const DEPTH: usize>() -> &'static [NodeHash] {
+    check_depth::<DEPTH>();
+    &empty_hashes()[256 - DEPTH..]
+}
+
 /// A  full Merkle Sum Sparse Merkle Tree. A full Merkle Tree that virtually contains
 /// all 2^256 nodes. This is made tractable by not storing empty nodes. In practice, we'll
 /// never have more than 2^64 nodes anyways.
 /// By being full, each element have exactly one possible position inside the tree, so you
 /// can prove statements like proof of non-inclusion (or proof of emptiness).
 /// This tree also commits to a value, and the root holds the sum of all leaves's values.
-pub struct MSSMTree<Persistence: TreeStore> {
+///
+/// `DEPTH` is how many branches there are between the root and a leaf. Only the first
+/// `DEPTH` bits of a key are used to place it, so shallower trees are for smaller keyspaces,
+/// e.g. 32 or 64 bit indexes. Those are created with [MSSMTree::with_depth]. The default,
+/// 256, is the tree used by taro, and the only one Go nodes understand.
+///
+/// Nodes are stored by hash, so two keys holding the same leaf share it, along with any
+/// identical branches under them. Deleting one of those keys removes the shared nodes, and
+/// the other key then fails with [Error::MissingNode]. If your keys can hold the same data
+/// and sum, wrap the storage in a [RefCountedStore] first.
+///
+/// [RefCountedStore]: super::ref_count::RefCountedStore
+pub struct MSSMTree<Persistence: TreeStore, const DEPTH: usize = 256> {
     /// A backend for our tree. We store nodes in key-value pairs.
     database: Persistence,
     /// Points to this tree's root
     root: NodeHash,
     /// This is used for optimization reasons. It contains the pre-computed values for
     /// an empty tree. So we can see what an empty value for each level looks like
-    empty_tree: Vec<Node>,
+    empty_tree: &'static [Node],
+    /// The hashes of `empty_tree`, to check if a subtree is empty
+    empty_hashes: &'static [NodeHash],
+    /// Trees opened at a past root can't be changed, only queried
+    read_only: bool,
+    /// If set, we never delete nodes that are no longer reachable from our root, so older
+    /// versions can still be opened with [MSSMTree::at_root]
+    keep_history: bool,
+    /// Where our nodes live inside the storage. Trees in different namespaces never see
+    /// each other's nodes, so many of them can share the same storage.
+    namespace: String,
+    /// If this tree was opened by name, we save our root in our namespace after each change
+    save_root: bool,
 }
-impl<Persistence: TreeStore> MSSMTree<Persistence> {
-    /// Returns this node's children hash. It can either be in an empty branch, so we return
-    /// the corresponding hash from the empty_tree. If this node isn't empty, we then return
-    /// it's actual child
-    fn get_children_hash(&self, node: &Option<DiskBranchNode>, idx: u8) -> (NodeHash, NodeHash) {
-        if let Some(node) = node {
-            if node.node_hash() != self.empty_tree[idx as usize].node_hash() {
-                return (*node.l_child(), *node.r_child());
+impl<Persistence: TreeStore, const DEPTH: usize> MSSMTree<Persistence, DEPTH> {
+    /// Returns the children hashes of `node`, a branch at depth `idx`. If this node is an
+    /// empty subtree, we already know it's children are empty too, so we take them from
+    /// the empty_tree without asking the storage. Otherwise we return it's actual children,
+    /// or [Error::MissingNode] if the storage doesn't have them.
+    fn children(
+        &self,
+        node: NodeHash,
+        idx: usize,
+    ) -> Result<(NodeHash, NodeHash), Error<Persistence::Error>> {
+        if node == self.empty_hashes[idx] {
+            let hash = self.empty_hashes[idx + 1];
+            return Ok((hash, hash));
+        }
+        match self
+            .database
+            .fetch_branch(&self.namespace, node)
+            .map_err(Error::Storage)?
+        {
+            Some(branch) => Ok((*branch.l_child(), *branch.r_child())),
+            None => Err(Error::MissingNode(node)),
+        }
+    }
+    /// Returns the node at `hash`, that sits at depth `idx`. Empty subtrees aren't stored,
+    /// so we return them straight from the empty_tree. Any other node we can't find is an
+    /// [Error::MissingNode].
+    fn fetch_node(&self, hash: NodeHash, idx: usize) -> Result<Node, Error<Persistence::Error>> {
+        if hash == self.empty_hashes[idx] {
+            return Ok(self.empty_tree[idx].clone());
+        }
+        let node = if idx == DEPTH {
+            self.database
+                .fetch_leaf(&self.namespace, hash)
+                .map_err(Error::Storage)?
+                .map(Node::Leaf)
+        } else {
+            self.database
+                .fetch_branch(&self.namespace, hash)
+                .map_err(Error::Storage)?
+                .map(Node::Branch)
+        };
+        node.ok_or(Error::MissingNode(hash))
+    }
+    /// Returns the hash of this tree's root
+    pub fn root_hash(&self) -> NodeHash {
+        self.root
+    }
+    /// Returns this tree's root node, that also holds the sum of all leaves
+    pub fn root(&self) -> Result<Node, Error<Persistence::Error>> {
+        self.fetch_node(self.root, 0)
+    }
+    /// Creates an empty tree with `DEPTH` levels, that keeps its nodes in `namespace`, e.g.
+    /// `MSSMTree::<_, 32>::with_depth(database, "index")`. Trees with different depths
+    /// have different roots, so they shouldn't share a namespace.
+    pub fn with_depth(database: Persistence, namespace: &str) -> MSSMTree<Persistence, DEPTH> {
+        let empty_hashes = empty_hashes_with_depth::<DEPTH>();
+        MSSMTree {
+            database,
+            root: empty_hashes[0],
+            empty_tree: empty_tree_with_depth::<DEPTH>(),
+            empty_hashes,
+            read_only: false,
+            keep_history: false,
+            namespace: namespace.to_owned(),
+            save_root: false,
+        }
+    }
+    /// Like [MSSMTree::open], for a tree with `DEPTH` levels
+    pub fn open_with_depth(
+        database: Persistence,
+        name: &str,
+    ) -> Result<MSSMTree<Persistence, DEPTH>, Error<Persistence::Error>> {
+        let mut tree = MSSMTree::with_depth(database, name);
+        if let Some(root) = tree.database.get_root(name).map_err(Error::Storage)? {
+            tree.root = root;
+        }
+        tree.save_root = true;
+        Ok(tree)
+    }
+    /// Like [MSSMTree::at_root_in], for a tree with `DEPTH` levels
+    pub fn at_root_with_depth(
+        database: Persistence,
+        namespace: &str,
+        root: NodeHash,
+    ) -> Result<MSSMTree<Persistence, DEPTH>, Error<Persistence::Error>> {
+        let mut tree = MSSMTree::with_depth(database, namespace);
+        if root != tree.root
+            && tree
+                .database
+                .fetch_branch(namespace, root)
+                .map_err(Error::Storage)?
+                .is_none()
+        {
+            return Err(Error::UnknownRoot(root));
+        }
+        tree.root = root;
+        tree.read_only = true;
+        Ok(tree)
+    }
+    /// The namespace this tree keeps its nodes in
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+    /// Returns the storage backing this tree
+    pub fn database(&self) -> &Persistence {
+        &self.database
+    }
+    /// Walks every node reachable from our root, checking that all of them are in the
+    /// storage. Returns [Error::MissingNode] with the first node we can't find. This visits
+    /// the whole tree, so it's meant for tests and audits, not for regular use.
+    pub fn check_consistency(&self) -> Result<(), Error<Persistence::Error>> {
+        let mut stack = vec![(self.root, 0)];
+        while let Some((node, idx)) = stack.pop() {
+            if node == self.empty_hashes[idx] {
+                continue;
+            }
+            if idx == DEPTH {
+                if self
+                    .database
+                    .fetch_leaf(&self.namespace, node)
+                    .map_err(Error::Storage)?
+                    .is_none()
+                {
+                    return Err(Error::MissingNode(node));
+                }
+                continue;
+            }
+            let branch = self
+                .database
+                .fetch_branch(&self.namespace, node)
+                .map_err(Error::Storage)?
+                .ok_or(Error::MissingNode(node))?;
+            stack.push((*branch.r_child(), idx + 1));
+            stack.push((*branch.l_child(), idx + 1));
+        }
+        Ok(())
+    }
+    /// Iterates over all leaves in this tree, ordered by their position in the tree
+    pub fn iter(&self) -> Leaves<'_, Persistence, RangeFull> {
+        self.range(..)
+    }
+    /// Iterates over all leaves with a key inside `range`. Keys are compared using
+    /// [NodeHash::cmp_path], so the order follows the tree, from left to right.
+    pub fn range<Range: RangeBounds<NodeHash>>(
+        &self,
+        range: Range,
+    ) -> Leaves<'_, Persistence, Range> {
+        Leaves::new(
+            &self.database,
+            &self.namespace,
+            self.empty_hashes,
+            self.root,
+            range,
+        )
+    }
+    /// Proves many keys at once, sharing the nodes their paths have in common. See
+    /// [MultiProof] for more details.
+    pub fn prove_many(
+        &self,
+        keys: &[NodeHash],
+    ) -> Result<MultiProof<DEPTH>, Error<Persistence::Error>> {
+        let mut keys = keys.to_vec();
+        keys.sort_by(|a, b| a.cmp_path(b));
+        keys.dedup();
+
+        let mut proof = MultiProof {
+            bits: vec![],
+            nodes: vec![],
+        };
+        self.prove_subtree(self.root, 0, &keys, &mut proof)?;
+        Ok(proof)
+    }
+    /// Adds all siblings we need to prove `keys` under the subtree at `node`, that sits at
+    /// depth `idx`. This must walk the tree in the same order as [MultiProof::root].
+    fn prove_subtree(
+        &self,
+        node: NodeHash,
+        idx: usize,
+        keys: &[NodeHash],
+        proof: &mut MultiProof<DEPTH>,
+    ) -> Result<(), Error<Persistence::Error>> {
+        if idx == DEPTH {
+            return Ok(());
+        }
+        let (left, right) = self.children(node, idx)?;
+
+        let split = keys.partition_point(|key| key.bit_index(idx as u8));
+        let (left_keys, right_keys) = keys.split_at(split);
+        for (child, keys) in [(left, left_keys), (right, right_keys)] {
+            if !keys.is_empty() {
+                self.prove_subtree(child, idx + 1, keys, proof)?;
+            } else if child == self.empty_hashes[idx + 1] {
+                proof.bits.push(true);
+            } else {
+                proof.bits.push(false);
+                proof.nodes.push(self.unchanged_child(child, idx + 1)?);
+            }
+        }
+        Ok(())
+    }
+    /// Inserts many leaves at once. This gives the same tree as calling [Tree::insert] for
+    /// each leaf in order, but keys sharing a path prefix also share the work of updating it,
+    /// so each changed branch is written exactly once. If a key shows up more than once,
+    /// the last value wins.
+    pub fn insert_batch(
+        &mut self,
+        leaves: impl IntoIterator<Item = (NodeHash, Vec<u8>, u64)>,
+    ) -> Result<(), Error<Persistence::Error>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut leaves: Vec<_> = leaves
+            .into_iter()
+            .map(|(key, data, sum)| (key, LeafNode::new(data, sum)))
+            .collect();
+        // Sort keys by their position in the tree, so all keys inside a subtree are next to
+        // each other. This sort is stable, so repeated keys are kept in insertion order.
+        leaves.sort_by(|(a, _), (b, _)| a.cmp_path(b));
+        let mut unique_leaves: Vec<(NodeHash, LeafNode)> = Vec::with_capacity(leaves.len());
+        for (key, leaf) in leaves {
+            match unique_leaves.last_mut() {
+                Some(last) if last.0 == key => last.1 = leaf,
+                _ => unique_leaves.push((key, leaf)),
+            }
+        }
+        if unique_leaves.is_empty() {
+            return Ok(());
+        }
+
+        // Like in [Tree::insert], we only touch the storage after all sums are checked
+        let mut changes = BatchChanges::default();
+        let new_root = self.update_subtree(self.root, 0, &unique_leaves, &mut changes)?;
+
+        self.commit(changes, new_root.node_hash())
+    }
+    /// Writes `changes` into the storage, moving our root to `new_root`
+    fn commit(
+        &mut self,
+        changes: BatchChanges,
+        new_root: NodeHash,
+    ) -> Result<(), Error<Persistence::Error>> {
+        let mut ops = changes.into_ops(self.keep_history);
+        if self.save_root {
+            ops.push(Op::SetRoot(new_root));
+        }
+        self.database
+            .apply_batch(&self.namespace, ops)
+            .map_err(Error::Storage)?;
+        self.root = new_root;
+        Ok(())
+    }
+    /// Inserts all `leaves` into the subtree rooted at `node`, that sits at depth `idx`.
+    /// `leaves` must be sorted with [NodeHash::cmp_path] and have no repeated keys. Returns
+    /// the new subtree root, and records what should be written in `changes`.
+    fn update_subtree(
+        &self,
+        node: NodeHash,
+        idx: usize,
+        leaves: &[(NodeHash, LeafNode)],
+        changes: &mut BatchChanges,
+    ) -> Result<Node, Error<Persistence::Error>> {
+        if idx == DEPTH {
+            let (_, leaf) = leaves.last().expect("We never recurse without leaves");
+            if node != self.empty_hashes[DEPTH] {
+                changes.deleted_leaves.push(node);
             }
+            if leaf.node_hash() != self.empty_hashes[DEPTH] {
+                changes.new_leaves.push(leaf.clone());
+            }
+            return Ok(Node::Leaf(leaf.clone()));
         }
 
-        let hash = self.empty_tree[((idx as usize) + 1)].node_hash();
+        let (left, right) = self.children(node, idx)?;
+
+        // Leaves going left come first, since they are sorted by path
+        let split = leaves.partition_point(|(key, _)| key.bit_index(idx as u8));
+        let (left_leaves, right_leaves) = leaves.split_at(split);
 
-        (hash, hash)
+        let left = if left_leaves.is_empty() {
+            self.unchanged_child(left, idx + 1)?
+        } else {
+            self.update_subtree(left, idx + 1, left_leaves, changes)?
+        };
+        let right = if right_leaves.is_empty() {
+            self.unchanged_child(right, idx + 1)?
+        } else {
+            self.update_subtree(right, idx + 1, right_leaves, changes)?
+        };
+
+        let sum = left
+            .node_sum()
+            .checked_add(right.node_sum())
+            .ok_or(SumOverflow)?;
+        let new_node = DiskBranchNode::new(sum, left.node_hash(), right.node_hash());
+
+        if node != self.empty_hashes[idx] {
+            changes.deleted_branches.push(node);
+        }
+        if new_node.node_hash() != self.empty_hashes[idx] {
+            changes.new_branches.push(new_node.clone());
+        }
+        Ok(Node::Branch(new_node))
+    }
+    /// Pulls the sum of a child we aren't touching, so we can compute it's parent's sum, or
+    /// use it as a sibling
+    fn unchanged_child(
+        &self,
+        hash: NodeHash,
+        idx: usize,
+    ) -> Result<Node, Error<Persistence::Error>> {
+        let sum = self.fetch_node(hash, idx)?.node_sum();
+        Ok(Node::Computed(ComputedNode::new(hash, sum)))
     }
+}
+
+impl<Persistence: TreeStore> MSSMTree<Persistence> {
+    /// Creates an empty tree in the [DEFAULT_NAMESPACE]
     pub fn new(database: Persistence) -> MSSMTree<Persistence> {
-        let mut empty_tree: Vec<Node> = Vec::with_capacity(257);
-        let mut node = Node::default();
-        empty_tree.push(node.clone());
-        // Creates the empty tree
-        for _ in 0..=255 {
-            let branch = Node::Branch(DiskBranchNode::new(0, node.node_hash(), node.node_hash()));
-            node = branch;
-            empty_tree.push(node.clone());
-        }
-        // We build it in reverse order, from leaf to root. But in a tree, index 0 is the root
-        // so we reverse that here.
-        let empty_tree: Vec<Node> = empty_tree.iter().cloned().rev().collect();
+        MSSMTree::with_namespace(database, DEFAULT_NAMESPACE)
+    }
+    /// Creates an empty tree that keeps its nodes in `namespace`. Trees in different
+    /// namespaces can share the same storage without touching each other's nodes, even
+    /// if they hold the same leaves.
+    pub fn with_namespace(database: Persistence, namespace: &str) -> MSSMTree<Persistence> {
+        MSSMTree::with_depth(database, namespace)
+    }
+    /// Opens the tree saved as `name`, or an empty one if there's no such tree yet. Each name
+    /// is it's own namespace. The root is saved in the storage together with every change,
+    /// so the tree can be opened again later, e.g. after a restart.
+    pub fn open(
+        database: Persistence,
+        name: &str,
+    ) -> Result<MSSMTree<Persistence>, Error<Persistence::Error>> {
+        MSSMTree::open_with_depth(database, name)
+    }
+    /// Creates a tree that keeps all its previous versions. Nodes are addressed by their
+    /// hash, so as long as we don't delete them, every root we had still points to a valid
+    /// tree. The downside is that the storage only grows.
+    ///
+    /// To open a previous version while this tree is alive, both trees should share the
+    /// storage, e.g. by passing a reference to it.
+    pub fn with_history(database: Persistence) -> MSSMTree<Persistence> {
         MSSMTree {
-            database,
-            root: empty_tree[0].node_hash(),
-            empty_tree,
+            keep_history: true,
+            ..MSSMTree::new(database)
         }
     }
+    /// Opens a read-only view of the tree with `root`, usually a past version of a tree
+    /// created with [MSSMTree::with_history]. Lookups, proofs and iteration work as usual,
+    /// but any change returns [Error::ReadOnly].
+    pub fn at_root(
+        database: Persistence,
+        root: NodeHash,
+    ) -> Result<MSSMTree<Persistence>, Error<Persistence::Error>> {
+        MSSMTree::at_root_in(database, DEFAULT_NAMESPACE, root)
+    }
+    /// Like [MSSMTree::at_root], for a tree living in `namespace`
+    pub fn at_root_in(
+        database: Persistence,
+        namespace: &str,
+        root: NodeHash,
+    ) -> Result<MSSMTree<Persistence>, Error<Persistence::Error>> {
+        MSSMTree::at_root_with_depth(database, namespace, root)
+    }
 }
-impl<Persistence: TreeStore> Tree<Persistence::Error> for MSSMTree<Persistence> {
-    fn insert(&mut self, key: NodeHash, data: Vec<u8>, sum: u64) -> Result<(), Persistence::Error> {
-        let leaf = LeafNode::new(data, sum);
 
-        let mut node = self.root;
-        let mut parents = vec![];
-        let mut siblings = vec![];
+/// Picks which child of a branch at depth `idx` the path to `key` goes through. Returns
+/// that child and it's sibling.
+pub(super) fn next_step(
+    key: &NodeHash,
+    idx: usize,
+    (left, right): (NodeHash, NodeHash),
+) -> (NodeHash, NodeHash) {
+    if key.bit_index(idx as u8) {
+        (left, right)
+    } else {
+        (right, left)
+    }
+}
 
-        // Walks down the tree and grabs all parents and siblings on the way down
-        for idx in 0..=255 {
-            let disk_node = self.database.fetch_branch(node)?;
-            let (left, right) = self.get_children_hash(&disk_node, idx);
+/// Hashes `leaf` up to the root, next to `siblings`, that start at the root's child like in
+/// a [Proof]. Returns the new branches on the path to `key`, root first. Nothing is written
+/// here, so an overflow can't leave a tree half-updated.
+pub(super) fn hash_path(
+    key: &NodeHash,
+    leaf: &LeafNode,
+    siblings: &[Node],
+) -> Result<Vec<DiskBranchNode>, SumOverflow> {
+    let mut new_branches = Vec::with_capacity(siblings.len());
+    let mut current_update = Node::Leaf(leaf.clone());
+    for (idx, sibling) in siblings.iter().enumerate().rev() {
+        let (left, right) = if key.bit_index(idx as u8) {
+            (current_update.node_hash(), sibling.node_hash())
+        } else {
+            (sibling.node_hash(), current_update.node_hash())
+        };
+        let sum = current_update
+            .node_sum()
+            .checked_add(sibling.node_sum())
+            .ok_or(SumOverflow)?;
 
-            let (next, sibling) = if key.bit_index(idx) {
-                (left, right)
-            } else {
-                (right, left)
-            };
+        let new_node = DiskBranchNode::new(sum, left, right);
+        current_update = Node::Branch(new_node.clone());
+        new_branches.push(new_node);
+    }
+    // We've built it from the leaf up, but paths start at the root
+    new_branches.reverse();
+    Ok(new_branches)
+}
 
-            parents.push(node);
-            siblings.push(sibling);
-            node = next;
-        }
+/// The kinds of leaves a tree can store, and how to write them
+pub(super) trait StoredLeaf {
+    /// The write that stores this leaf
+    fn insert_op(self) -> Op;
+    /// The write that removes the leaf at `hash`
+    fn delete_op(hash: NodeHash) -> Op;
+}
 
-        if leaf.node_hash() != self.empty_tree[255].node_hash() {
-            self.database.insert_leaf(leaf.clone())?;
-        } else {
-            self.database.delete_leaf(leaf.node_hash())?;
+impl StoredLeaf for LeafNode {
+    fn insert_op(self) -> Op {
+        Op::InsertLeaf(self)
+    }
+    fn delete_op(hash: NodeHash) -> Op {
+        Op::DeleteLeaf(hash)
+    }
+}
+
+impl StoredLeaf for CompactedLeafNode {
+    fn insert_op(self) -> Op {
+        Op::InsertCompactedLeaf(self)
+    }
+    fn delete_op(hash: NodeHash) -> Op {
+        Op::DeleteCompactedLeaf(hash)
+    }
+}
+
+/// Everything an insertion should write to the storage, once we know it is valid. Full
+/// trees store [LeafNode]s, compacted trees store [CompactedLeafNode]s.
+pub(super) struct BatchChanges<Leaf: StoredLeaf = LeafNode> {
+    pub(super) deleted_leaves: Vec<NodeHash>,
+    pub(super) deleted_branches: Vec<NodeHash>,
+    pub(super) new_leaves: Vec<Leaf>,
+    pub(super) new_branches: Vec<DiskBranchNode>,
+}
+
+impl<Leaf: StoredLeaf> Default for BatchChanges<Leaf> {
+    fn default() -> Self {
+        BatchChanges {
+            deleted_leaves: vec![],
+            deleted_branches: vec![],
+            new_leaves: vec![],
+            new_branches: vec![],
         }
-        let mut current_update: Node = Node::Leaf(leaf);
+    }
+}
 
-        // Actually update the tree
-        for idx in (1..=255).rev() {
-            let sibling = siblings.pop().unwrap();
-            let (left, right) = if key.bit_index(idx) {
-                (current_update.node_hash(), sibling)
-            } else {
-                (sibling, current_update.node_hash())
-            };
+impl<Leaf: StoredLeaf> BatchChanges<Leaf> {
+    /// Turns these changes into a single batch for the storage, deletes first. If
+    /// `keep_history` is set, nothing is deleted.
+    pub(super) fn into_ops(self, keep_history: bool) -> Vec<Op> {
+        let mut ops = vec![];
+        if !keep_history {
+            ops.extend(self.deleted_leaves.into_iter().map(Leaf::delete_op));
+            ops.extend(self.deleted_branches.into_iter().map(Op::DeleteBranch));
+        }
+        ops.extend(self.new_leaves.into_iter().map(Leaf::insert_op));
+        ops.extend(self.new_branches.into_iter().map(Op::InsertBranch));
+        ops
+    }
+}
 
-            let sibling = self.database.fetch_branch(sibling)?;
-            let sum = if let Some(sibling) = sibling {
-                current_update.node_sum() + sibling.node_sum()
-            } else {
-                current_update.node_sum()
-            };
-            // If the old node isn't empty, delete it from the storage
-            if parents[(idx - 1) as usize] != self.empty_tree[(idx - 1) as usize].node_hash() {
-                self.database.delete_branch(parents[idx as usize])?;
+impl BatchChanges {
+    /// The changes for replacing a single path, from `old_path` and `old_leaf` to
+    /// `new_path` and `new_leaf`. Paths start at the root, and `empty_hashes` is the empty
+    /// tree they live in. Empty nodes are never stored, so they are neither deleted nor
+    /// inserted.
+    pub(super) fn for_path(
+        empty_hashes: &[NodeHash],
+        old_path: Vec<NodeHash>,
+        old_leaf: NodeHash,
+        new_path: Vec<DiskBranchNode>,
+        new_leaf: LeafNode,
+    ) -> BatchChanges {
+        let depth = old_path.len();
+        let mut changes = BatchChanges::default();
+        if old_leaf != empty_hashes[depth] {
+            changes.deleted_leaves.push(old_leaf);
+        }
+        if new_leaf.node_hash() != empty_hashes[depth] {
+            changes.new_leaves.push(new_leaf);
+        }
+        for (idx, (old_node, new_node)) in old_path.into_iter().zip(new_path).enumerate() {
+            if old_node != empty_hashes[idx] {
+                changes.deleted_branches.push(old_node);
             }
-            let new_node = DiskBranchNode::new(sum, left, right);
-            // If the new node isn't empty, add it into the storage
-            if new_node.node_hash() != self.empty_tree[idx as usize].node_hash() {
-                self.database.insert_branch(new_node.clone())?;
+            if new_node.node_hash() != empty_hashes[idx] {
+                changes.new_branches.push(new_node);
             }
-            current_update = Node::Branch(new_node);
         }
-        self.root = current_update.node_hash();
-        Ok(())
+        changes
     }
+}
+
+impl<Persistence: TreeStore, const DEPTH: usize> Tree<Error<Persistence::Error>>
+    for MSSMTree<Persistence, DEPTH>
+{
+    fn insert(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let leaf = LeafNode::new(data, sum);
 
-    fn delete(&mut self, key: NodeHash) -> Result<(), Persistence::Error> {
+        let mut node = self.root;
+        let mut parents = Vec::with_capacity(DEPTH);
+        let mut siblings = Vec::with_capacity(DEPTH);
+
+        // Walks down the tree and grabs all parents and siblings on the way down
+        for idx in 0..DEPTH {
+            let (next, sibling) = next_step(&key, idx, self.children(node, idx)?);
+            parents.push(node);
+            siblings.push(self.unchanged_child(sibling, idx + 1)?);
+            node = next;
+        }
+        // `node` is now the old leaf, that is being replaced
+        let new_path = hash_path(&key, &leaf, &siblings)?;
+        let new_root = new_path[0].node_hash();
+
+        // Actually update the tree, all at once
+        let changes = BatchChanges::for_path(self.empty_hashes, parents, node, new_path, leaf);
+        self.commit(changes, new_root)
+    }
+
+    fn delete(&mut self, key: NodeHash) -> Result<(), Error<Persistence::Error>> {
         self.insert(key, vec![], 0)
     }
 
-    fn update(&mut self, key: NodeHash, data: Vec<u8>, sum: u64) -> Result<(), Persistence::Error> {
+    fn update(
+        &mut self,
+        key: NodeHash,
+        data: Vec<u8>,
+        sum: u64,
+    ) -> Result<(), Error<Persistence::Error>> {
         self.insert(key, data, sum)
     }
 
-    fn lookup(&self, key: NodeHash) -> Result<Option<LeafNode>, Persistence::Error> {
+    fn lookup(&self, key: NodeHash) -> Result<Option<LeafNode>, Error<Persistence::Error>> {
         let mut node = self.root;
-        for idx in 0..=254 {
-            let disk_node = self.database.fetch_branch(node)?;
-            let (left, right) = self.get_children_hash(&disk_node, idx);
-            let next = if key.bit_index(idx) { left } else { right };
-            node = next;
+        for idx in 0..DEPTH {
+            (node, _) = next_step(&key, idx, self.children(node, idx)?);
+        }
+        if node == self.empty_hashes[DEPTH] {
+            return Ok(None);
         }
-        Ok(self.database.fetch_leaf(node)?)
+        self.database
+            .fetch_leaf(&self.namespace, node)
+            .map_err(Error::Storage)
     }
 }
 
-impl<T: TreeStore> Provable for MSSMTree<T> {
-    type Error = T::Error;
+impl<T: TreeStore, const DEPTH: usize> Provable<DEPTH> for MSSMTree<T, DEPTH> {
+    type Error = Error<T::Error>;
 
-    fn prove(&self, key: NodeHash) -> Result<Proof, Self::Error> {
-        let mut proof = Vec::new();
-        let mut node = self.root;
-        for idx in 0..=255 {
-            let disk_node = self.database.fetch_branch(node)?;
-            let (left, right) = self.get_children_hash(&disk_node, idx as u8);
+    fn prove_absence(&self, key: NodeHash) -> Result<Option<Proof<DEPTH>>, Self::Error> {
+        if self.lookup(key)?.is_some() {
+            return Ok(None);
+        }
+        self.prove(key).map(Some)
+    }
 
-            let (next, sibling) = if key.bit_index(idx as u8) {
-                (left, right)
-            } else {
-                (right, left)
-            };
+    fn prove(&self, key: NodeHash) -> Result<Proof<DEPTH>, Self::Error> {
+        let mut proof = Vec::with_capacity(DEPTH);
+        let mut node = self.root;
+        for idx in 0..DEPTH {
+            let (next, sibling) = next_step(&key, idx, self.children(node, idx)?);
             node = next;
-            if idx < 255 {
-                if let Some(sibling) = self.database.fetch_branch(sibling)? {
-                    proof.push(Node::Branch(sibling));
-                } else {
-                    proof.push(self.empty_tree[(idx + 1) as usize].clone());
-                }
-            } else {
-                if let Some(sibling) = self.database.fetch_leaf(sibling)? {
-                    proof.push(Node::Leaf(sibling));
-                } else {
-                    proof.push(self.empty_tree[(idx + 1) as usize].clone());
-                }
-            }
+            proof.push(self.fetch_node(sibling, idx + 1)?);
         }
 
-        Ok(Proof::new(proof))
+        Ok(Proof::with_depth(proof))
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::mssmt::{
+        cache::CachedStore,
+        error::Error,
         memory_db::MemoryDatabase,
-        node::{LeafNode, MSSMTNode},
+        node::{ComputedNode, LeafNode, MSSMTNode},
         node_hash::NodeHash,
+        proof::{Provable, Verifiable},
+        tree_backend::{Op, TreeStore, DEFAULT_NAMESPACE},
     };
+    use sha2::Digest;
     fn get_test_tree() -> MSSMTree<MemoryDatabase> {
         let database = MemoryDatabase::new();
 
         MSSMTree::new(database)
     }
-    use super::{MSSMTree, Tree};
+    use super::{empty_hashes, MSSMTree, Tree};
     #[test]
     fn test_addition() {
         let leaf = LeafNode::new(vec![b'S', b'a', b't', b'o', b's', b'h', b'i'], 1984);
         let expected_hash = leaf.node_hash();
         let expected_root =
-            NodeHash::try_from("fe954176caf85b7dd0e82a4377902faed05cb165fbb6e30c03b488bde7c1e457")
+            NodeHash::try_from("fe7917b2f00e3192692c0b1411cfe1d5527ab0e34bf76cde295417b558045cd5")
                 .unwrap();
         let mut tree = get_test_tree();
 
@@ -250,6 +774,363 @@ mod test {
         assert_eq!(leaf.node_hash(), expected_hash);
     }
     #[test]
+    fn test_many_leaves() {
+        let mut tree = get_test_tree();
+        for i in 0..10_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i], i as u64)
+                .unwrap();
+        }
+        for i in 0..10_u8 {
+            let leaf = tree.lookup(NodeHash::from([i; 32])).unwrap().unwrap();
+            assert_eq!(leaf.node_sum(), i as u64);
+
+            let proof = tree.prove(NodeHash::from([i; 32])).unwrap();
+            let root = proof.verify(&leaf, &NodeHash::from([i; 32])).unwrap();
+            assert_eq!(root, tree.root);
+        }
+        // Deleting everything gets us back to the empty tree
+        for i in 0..10_u8 {
+            tree.delete(NodeHash::from([i; 32])).unwrap();
+        }
+        assert_eq!(tree.root, tree.empty_hashes[0]);
+    }
+    #[test]
+    fn test_root_sum() {
+        let mut tree = get_test_tree();
+        assert_eq!(tree.root().unwrap().node_sum(), 0);
+
+        tree.insert(NodeHash::from([0; 32]), vec![1], 99).unwrap();
+        tree.insert(NodeHash::from([1; 32]), vec![2], 1).unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!(root.node_hash(), tree.root_hash());
+        assert_eq!(root.node_sum(), 100);
+
+        let leaf = LeafNode::new(vec![1], 99);
+        let proof = tree.prove(NodeHash::from([0; 32])).unwrap();
+        assert!(proof
+            .verify_root(&leaf, &NodeHash::from([0; 32]), &root)
+            .unwrap());
+
+        // Same hash, but a different sum
+        let proof = tree.prove(NodeHash::from([0; 32])).unwrap();
+        let inflated = ComputedNode::new(root.node_hash(), 101);
+        assert!(!proof
+            .verify_root(&leaf, &NodeHash::from([0; 32]), &inflated)
+            .unwrap());
+    }
+    #[test]
+    fn test_sum_overflow() {
+        let mut tree = get_test_tree();
+        tree.insert(NodeHash::from([0; 32]), vec![1], u64::MAX)
+            .unwrap();
+        let root = tree.root_hash();
+
+        let res = tree.insert(NodeHash::from([1; 32]), vec![2], 1);
+        assert!(matches!(res, Err(Error::SumOverflow)));
+        // The tree is left untouched
+        assert_eq!(tree.root_hash(), root);
+        assert!(tree.lookup(NodeHash::from([1; 32])).unwrap().is_none());
+        assert!(tree.lookup(NodeHash::from([0; 32])).unwrap().is_some());
+    }
+    #[test]
+    fn test_non_inclusion() {
+        let mut tree = get_test_tree();
+        tree.insert(NodeHash::from([0; 32]), vec![1], 99).unwrap();
+
+        let proof = tree
+            .prove_absence(NodeHash::from([1; 32]))
+            .unwrap()
+            .expect("Key isn't in the tree");
+        assert!(proof
+            .verify_non_inclusion(&NodeHash::from([1; 32]), &tree.root)
+            .unwrap());
+
+        // We can't prove the absence of something that is in the tree
+        assert!(tree
+            .prove_absence(NodeHash::from([0; 32]))
+            .unwrap()
+            .is_none());
+        let proof = tree.prove(NodeHash::from([0; 32])).unwrap();
+        assert!(!proof
+            .verify_non_inclusion(&NodeHash::from([0; 32]), &tree.root)
+            .unwrap());
+    }
+    #[test]
+    fn test_insert_batch() {
+        let leaves: Vec<_> = (0..100_u8)
+            .map(|i| {
+                let key: [u8; 32] = sha2::Sha256::digest([i]).into();
+                (NodeHash::from(key), vec![i], i as u64)
+            })
+            .collect();
+
+        let mut sequential = get_test_tree();
+        sequential.insert(leaves[0].0, vec![42], 42).unwrap();
+        for (key, data, sum) in leaves.iter().cloned() {
+            sequential.insert(key, data, sum).unwrap();
+        }
+        sequential.delete(leaves[1].0).unwrap();
+
+        let mut batched = get_test_tree();
+        batched.insert(leaves[0].0, vec![42], 42).unwrap();
+        let mut batch = leaves.clone();
+        // Repeated keys should behave like sequential inserts
+        batch.push((leaves[1].0, vec![], 0));
+        batched.insert_batch(batch).unwrap();
+
+        assert_eq!(batched.root_hash(), sequential.root_hash());
+        assert_eq!(
+            batched.root().unwrap().node_sum(),
+            (0..100).sum::<u64>() - 1
+        );
+        for (key, data, sum) in leaves.into_iter().skip(2) {
+            let leaf = batched.lookup(key).unwrap().expect("We've inserted this");
+            assert_eq!(leaf.node_hash(), LeafNode::new(data, sum).node_hash());
+        }
+        // An empty batch doesn't change anything
+        batched.insert_batch(vec![]).unwrap();
+        assert_eq!(batched.root_hash(), sequential.root_hash());
+    }
+    #[test]
+    fn test_iter() {
+        let mut tree = get_test_tree();
+        assert!(tree.iter().next().is_none());
+
+        let mut keys: Vec<NodeHash> = (0..20_u8)
+            .map(|i| {
+                let key: [u8; 32] = sha2::Sha256::digest([i]).into();
+                key.into()
+            })
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(*key, vec![i as u8], i as u64).unwrap();
+        }
+        keys.sort_by(|a, b| a.cmp_path(b));
+
+        let leaves: Vec<_> = tree.iter().map(Result::unwrap).collect();
+        assert_eq!(leaves.len(), 20);
+        for ((key, leaf), expected) in leaves.iter().zip(keys.iter()) {
+            assert_eq!(key, expected);
+            let stored = tree.lookup(*key).unwrap().unwrap();
+            assert_eq!(leaf.node_hash(), stored.node_hash());
+        }
+
+        let range: Vec<_> = tree
+            .range(keys[5]..keys[10])
+            .map(|leaf| leaf.unwrap().0)
+            .collect();
+        assert_eq!(range, keys[5..10]);
+        let range: Vec<_> = tree.range(keys[15]..).map(|leaf| leaf.unwrap().0).collect();
+        assert_eq!(range, keys[15..]);
+    }
+    #[test]
+    fn test_history() {
+        let database = MemoryDatabase::new();
+        let mut tree = MSSMTree::with_history(&database);
+        let mut roots = vec![tree.root().unwrap()];
+        for i in 0..5_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i], i as u64)
+                .unwrap();
+            roots.push(tree.root().unwrap());
+        }
+        tree.delete(NodeHash::from([0; 32])).unwrap();
+
+        for (version, root) in roots.iter().enumerate() {
+            let mut old_tree = MSSMTree::at_root(&database, root.node_hash()).unwrap();
+            assert_eq!(old_tree.root().unwrap().node_sum(), root.node_sum());
+            for i in 0..5_u8 {
+                let key = NodeHash::from([i; 32]);
+                let leaf = old_tree.lookup(key).unwrap();
+                assert_eq!(leaf.is_some(), (i as usize) < version);
+
+                let leaf = leaf.unwrap_or_default();
+                let proof = old_tree.prove(key).unwrap();
+                assert!(proof.verify_root(&leaf, &key, root).unwrap());
+            }
+            let res = old_tree.insert(NodeHash::from([9; 32]), vec![9], 9);
+            assert!(matches!(res, Err(Error::ReadOnly)));
+        }
+    }
+    #[test]
+    fn test_open() {
+        let database = MemoryDatabase::new();
+        let root = {
+            let mut tree = MSSMTree::open(&database, "assets").unwrap();
+            tree.insert(NodeHash::from([0; 32]), vec![0], 10).unwrap();
+            tree.insert_batch([(NodeHash::from([1; 32]), vec![1], 20)])
+                .unwrap();
+            tree.root_hash()
+        };
+        let tree = MSSMTree::open(&database, "assets").unwrap();
+        assert_eq!(tree.root_hash(), root);
+        assert_eq!(tree.root().unwrap().node_sum(), 30);
+        assert!(tree.lookup(NodeHash::from([1; 32])).unwrap().is_some());
+
+        // Other names are different trees
+        let tree = MSSMTree::open(&database, "other").unwrap();
+        assert_eq!(tree.root_hash(), MSSMTree::new(&database).root_hash());
+    }
+    #[test]
+    fn test_namespaces() {
+        let database = MemoryDatabase::new();
+        let mut first = MSSMTree::with_namespace(&database, "first");
+        let mut second = MSSMTree::with_namespace(&database, "second");
+        // Both trees have the same nodes, but they are stored twice
+        for tree in [&mut first, &mut second] {
+            tree.insert(NodeHash::from([0; 32]), vec![0], 1).unwrap();
+            tree.insert(NodeHash::from([1; 32]), vec![1], 2).unwrap();
+        }
+        assert_eq!(first.root_hash(), second.root_hash());
+
+        // Deleting from one tree doesn't touch the other
+        first.delete(NodeHash::from([0; 32])).unwrap();
+        second.check_consistency().unwrap();
+        assert!(second.lookup(NodeHash::from([0; 32])).unwrap().is_some());
+        assert_eq!(second.root().unwrap().node_sum(), 3);
+
+        // Nor does a tree in the default namespace see them
+        let tree = MSSMTree::new(&database);
+        assert!(tree.lookup(NodeHash::from([1; 32])).unwrap().is_none());
+        let res = MSSMTree::at_root(&database, second.root_hash());
+        assert!(matches!(res, Err(Error::UnknownRoot(_))));
+        let old = MSSMTree::at_root_in(&database, "second", second.root_hash()).unwrap();
+        assert_eq!(old.namespace(), "second");
+        assert_eq!(old.iter().count(), 2);
+    }
+    #[test]
+    fn test_empty_subtrees() {
+        // Without any space, every fetch goes to the database and counts as a miss
+        let database = CachedStore::new(MemoryDatabase::new(), 0);
+        let mut tree = MSSMTree::new(&database);
+        let first = NodeHash::from([0; 32]);
+        let last = NodeHash::from([0xff; 32]);
+
+        // Everything is empty, so there's nothing to fetch
+        assert!(tree.lookup(first).unwrap().is_none());
+        tree.prove(first).unwrap();
+        tree.insert(first, vec![0], 1).unwrap();
+        assert_eq!(database.misses(), 0);
+
+        // Both keys split at the root, so we only need the root and the first key's subtree
+        assert!(tree.lookup(last).unwrap().is_none());
+        assert_eq!(database.misses(), 1);
+        tree.prove(last).unwrap();
+        assert_eq!(database.misses(), 3);
+        tree.insert(last, vec![1], 2).unwrap();
+        assert_eq!(database.misses(), 5);
+
+        let leaf = tree.lookup(last).unwrap().unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!(root.node_sum(), 3);
+        assert!(tree
+            .prove(last)
+            .unwrap()
+            .verify_root(&leaf, &last, &root)
+            .unwrap());
+    }
+    #[test]
+    fn test_shallow_tree() {
+        let database = MemoryDatabase::new();
+        let mut tree = MSSMTree::<_, 8>::open_with_depth(&database, "shallow").unwrap();
+        // An empty shallow tree is the bottom of an empty full tree
+        assert_eq!(tree.root_hash(), empty_hashes()[256 - 8]);
+
+        // Only the first byte is part of the path
+        let keys: Vec<_> = (0..10_u8).map(|i| NodeHash::from([i; 32])).collect();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(*key, vec![i as u8], i as u64).unwrap();
+        }
+        let mut alias = [1; 32];
+        alias[31] = 0;
+        let leaf = tree.lookup(NodeHash::from(alias)).unwrap().unwrap();
+        assert_eq!(leaf.node_hash(), LeafNode::new(vec![1], 1).node_hash());
+        tree.check_consistency().unwrap();
+
+        let root = tree.root().unwrap();
+        assert_eq!(root.node_sum(), 45);
+        for (i, key) in keys.iter().enumerate() {
+            let proof = tree.prove(*key).unwrap();
+            assert_eq!(proof.nodes().len(), 8);
+            let leaf = LeafNode::new(vec![i as u8], i as u64);
+            assert!(proof.verify_root(&leaf, key, &root).unwrap());
+        }
+        let proof = tree
+            .prove_absence(NodeHash::from([20; 32]))
+            .unwrap()
+            .unwrap();
+        assert!(proof
+            .verify_non_inclusion(&NodeHash::from([20; 32]), &tree.root_hash())
+            .unwrap());
+        let leaves: Vec<_> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (*key, LeafNode::new(vec![i as u8], i as u64)))
+            .collect();
+        let proof = tree.prove_many(&keys).unwrap();
+        assert!(proof.verify(&leaves, &root).unwrap());
+
+        // Keys we get back only have the bits we've used
+        let found: Vec<_> = tree.iter().map(|leaf| leaf.unwrap().0).collect();
+        assert_eq!(found.len(), 10);
+        for key in found {
+            assert!(key[1..].iter().all(|byte| *byte == 0));
+        }
+
+        let mut batched = MSSMTree::<_, 8>::with_depth(MemoryDatabase::new(), "batched");
+        batched
+            .insert_batch(
+                keys.iter()
+                    .enumerate()
+                    .map(|(i, key)| (*key, vec![i as u8], i as u64)),
+            )
+            .unwrap();
+        assert_eq!(batched.root_hash(), tree.root_hash());
+
+        // The root was saved, so we can open it again
+        let opened = MSSMTree::<_, 8>::open_with_depth(&database, "shallow").unwrap();
+        assert_eq!(opened.root_hash(), tree.root_hash());
+        let old =
+            MSSMTree::<_, 8>::at_root_with_depth(&database, "shallow", tree.root_hash()).unwrap();
+        assert_eq!(old.root().unwrap().node_sum(), 45);
+
+        for key in keys {
+            tree.delete(key).unwrap();
+        }
+        assert_eq!(tree.root_hash(), empty_hashes()[256 - 8]);
+        assert_eq!(database.len().unwrap(), 0);
+    }
+    #[test]
+    fn test_unknown_root() {
+        let database = MemoryDatabase::new();
+        let mut tree = MSSMTree::new(&database);
+        tree.insert(NodeHash::from([0; 32]), vec![0], 1).unwrap();
+        let old_root = tree.root_hash();
+        tree.insert(NodeHash::from([1; 32]), vec![1], 1).unwrap();
+
+        // Without history, the old root was deleted
+        let res = MSSMTree::at_root(&database, old_root);
+        assert!(matches!(res, Err(Error::UnknownRoot(root)) if root == old_root));
+        assert!(MSSMTree::at_root(&database, tree.root_hash()).is_ok());
+    }
+    #[test]
+    fn test_missing_node() {
+        let database = MemoryDatabase::new();
+        let mut tree = MSSMTree::new(&database);
+        tree.insert(NodeHash::from([0; 32]), vec![0], 1).unwrap();
+        let root = tree.root_hash();
+        database
+            .apply_batch(DEFAULT_NAMESPACE, vec![Op::DeleteBranch(root)])
+            .unwrap();
+
+        // A node that isn't empty must be stored, we never read it as an empty subtree
+        let missing = |res| matches!(res, Err(Error::MissingNode(hash)) if hash == root);
+        assert!(missing(tree.root().map(|_| ())));
+        assert!(missing(tree.lookup(NodeHash::from([0; 32])).map(|_| ())));
+        assert!(missing(tree.prove(NodeHash::from([0; 32])).map(|_| ())));
+        assert!(missing(tree.iter().next().unwrap().map(|_| ())));
+        assert!(missing(tree.insert(NodeHash::from([1; 32]), vec![1], 1)));
+    }
+    #[test]
     fn test_empty_tree() {
         // Tests if our empty tree is correct. This hashes was obtained using this Go code:
         //```go
@@ -281,5 +1162,11 @@ mod test {
             // assert that each i-th position is pairwise equal
             assert_eq!(left.node_hash(), *right, "node {i} diverges");
         }
+        assert_eq!(empty_hashes(), hashes);
+
+        // Every tree shares the same table
+        let other = get_test_tree();
+        assert!(std::ptr::eq(tree.empty_tree, other.empty_tree));
+        assert!(std::ptr::eq(tree.empty_hashes, empty_hashes()));
     }
 }
@@ -8,27 +8,364 @@
 //! can be computed efficiently ahead of time, this saves up space and makes the tree more
 //! tractable.
 //!
-use super::node::{BranchNode, DiskBranchNode, LeafNode};
+use super::node::{BranchNode, CompactedLeafNode, ComputedNode, DiskBranchNode, LeafNode, Node};
 use super::node_hash::NodeHash;
 
+/// The namespace used by trees that don't ask for one
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// A single write into a [TreeStore], see [TreeStore::apply_batch]
+#[derive(Debug, Clone)]
+pub enum Op {
+    InsertBranch(DiskBranchNode),
+    InsertLeaf(LeafNode),
+    InsertCompactedLeaf(CompactedLeafNode),
+    DeleteBranch(NodeHash),
+    DeleteLeaf(NodeHash),
+    DeleteCompactedLeaf(NodeHash),
+    /// Saves the root of the tree living in this namespace
+    SetRoot(NodeHash),
+    /// Saves how many references a node in this namespace has, zero removes it. Those are
+    /// only used by [RefCountedStore](super::ref_count::RefCountedStore).
+    SetRefCount(NodeHash, u64),
+}
+
+/// Every node lives inside a namespace, usually one for each tree. The same node may be
+/// stored in many namespaces, and reading or deleting it in one of them never touches the
+/// others. So many trees can share the same backend.
 pub trait TreeStore {
     type Error;
+    /// Applies all `ops`, in order and inside `namespace`, as a single atomic write. Either
+    /// all of them are applied, or none is, so a crash or an error never leaves the storage
+    /// half-updated. Trees use this for every change they make.
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error>;
+    /// Returns the root saved for the tree in `namespace`, if any
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error>;
+    /// Saves `root` as the root of the tree in `namespace`. Trees do this as part of their
+    /// batches, so the root always points to nodes that are in the storage.
+    fn set_root(&self, namespace: &str, root: NodeHash) -> Result<(), Self::Error> {
+        self.apply_batch(namespace, vec![Op::SetRoot(root)])
+    }
+    /// Returns the reference count saved for `hash` in `namespace`, zero if there's none.
+    /// Counts are saved with [Op::SetRefCount].
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error>;
     /// Stores a new branch keyed by its node_hash. Branch nodes are intermediate nodes
     /// that aren't a root or a leaf (i.e nodes in 1 <= i < 255).
-    fn insert_branch(&self, branch: DiskBranchNode) -> Result<(), Self::Error>;
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error>;
     /// Inserts a new leaf into our storage
-    fn insert_leaf(&self, leaf: LeafNode) -> Result<(), Self::Error>;
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error>;
     /// delete_branch deletes the branch node keyed by the given NodeHash.
-    fn delete_branch(&self, hash: NodeHash) -> Result<(), Self::Error>;
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error>;
     /// delete_leaf deletes the leaf node keyed by the given NodeHash.
-    fn delete_leaf(&self, hash: NodeHash) -> Result<(), Self::Error>;
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error>;
     /// Fetches a branch node from storage. This method only fetches one node and
     /// the id of it's children. To get the actual child, you need to fetch again.
-    fn fetch_branch(&self, hash: NodeHash) -> Result<Option<DiskBranchNode>, Self::Error>;
-    /// Fetches a branch node from storage. This method will also pull every children in
-    /// the subtree. So if a node have subtree depth of 5, all 5 levels will be fetched.
-    /// This might cause some memory issues for bigger subtrees.
-    fn fetch_branch_recursive(&self, hash: NodeHash) -> Result<Option<BranchNode>, Self::Error>;
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error>;
+    /// Fetches a branch node from storage. This method will also pull the children in
+    /// the subtree, up to `max_depth` levels below this node. Branches at the limit are
+    /// returned as [Node::Branch], with only their children hashes. Use `usize::MAX` to pull
+    /// the whole subtree, but this might cause some memory issues for bigger subtrees.
+    ///
+    /// Empty subtrees aren't stored, so children we can't find are returned as a
+    /// [Node::Computed] with zero sum.
+    fn fetch_branch_recursive(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+        max_depth: usize,
+    ) -> Result<Option<BranchNode>, Self::Error> {
+        match self.fetch_branch(namespace, hash)? {
+            Some(branch) => expand_branch(self, namespace, branch, max_depth).map(Some),
+            None => Ok(None),
+        }
+    }
     /// Fetches a leaf node from internal storage.
-    fn fetch_leaf(&self, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error>;
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error>;
+    /// Stores a leaf that lives above the bottom of a compacted tree, keyed by the hash
+    /// of the subtree it represents.
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error>;
+    /// delete_compacted_leaf deletes the compacted leaf keyed by the given NodeHash.
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error>;
+    /// Fetches a compacted leaf from internal storage.
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error>;
+}
+
+/// Pulls the children of `branch` from `store`, going at most `max_depth` levels down
+fn expand_branch<Store: TreeStore + ?Sized>(
+    store: &Store,
+    namespace: &str,
+    branch: DiskBranchNode,
+    max_depth: usize,
+) -> Result<BranchNode, Store::Error> {
+    let left = fetch_child(store, namespace, *branch.l_child(), max_depth)?;
+    let right = fetch_child(store, namespace, *branch.r_child(), max_depth)?;
+    Ok(BranchNode::from_disk(&branch, left, right))
+}
+
+/// Fetches a node we only know the hash of. We don't know the node's depth, so we look for
+/// it as a branch, then as a leaf.
+fn fetch_child<Store: TreeStore + ?Sized>(
+    store: &Store,
+    namespace: &str,
+    hash: NodeHash,
+    max_depth: usize,
+) -> Result<Node, Store::Error> {
+    if let Some(branch) = store.fetch_branch(namespace, hash)? {
+        if max_depth == 0 {
+            return Ok(Node::Branch(branch));
+        }
+        let branch = expand_branch(store, namespace, branch, max_depth - 1)?;
+        return Ok(Node::Subtree(Box::new(branch)));
+    }
+    if let Some(leaf) = store.fetch_leaf(namespace, hash)? {
+        return Ok(Node::Leaf(leaf));
+    }
+    if let Some(leaf) = store.fetch_compacted_leaf(namespace, hash)? {
+        return Ok(Node::Compacted(leaf));
+    }
+    Ok(Node::Computed(ComputedNode::new(hash, 0)))
+}
+
+/// Trees own their store, so passing `&store` instead lets many of them, usually in different
+/// namespaces, share one backend
+impl<T: TreeStore> TreeStore for &T {
+    type Error = T::Error;
+
+    fn apply_batch(&self, namespace: &str, ops: Vec<Op>) -> Result<(), Self::Error> {
+        (**self).apply_batch(namespace, ops)
+    }
+    fn get_root(&self, namespace: &str) -> Result<Option<NodeHash>, Self::Error> {
+        (**self).get_root(namespace)
+    }
+    fn set_root(&self, namespace: &str, root: NodeHash) -> Result<(), Self::Error> {
+        (**self).set_root(namespace, root)
+    }
+    fn get_ref_count(&self, namespace: &str, hash: &NodeHash) -> Result<u64, Self::Error> {
+        (**self).get_ref_count(namespace, hash)
+    }
+    fn insert_branch(&self, namespace: &str, branch: DiskBranchNode) -> Result<(), Self::Error> {
+        (**self).insert_branch(namespace, branch)
+    }
+    fn insert_leaf(&self, namespace: &str, leaf: LeafNode) -> Result<(), Self::Error> {
+        (**self).insert_leaf(namespace, leaf)
+    }
+    fn delete_branch(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        (**self).delete_branch(namespace, hash)
+    }
+    fn delete_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        (**self).delete_leaf(namespace, hash)
+    }
+    fn fetch_branch(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<DiskBranchNode>, Self::Error> {
+        (**self).fetch_branch(namespace, hash)
+    }
+    fn fetch_branch_recursive(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+        max_depth: usize,
+    ) -> Result<Option<BranchNode>, Self::Error> {
+        (**self).fetch_branch_recursive(namespace, hash, max_depth)
+    }
+    fn fetch_leaf(&self, namespace: &str, hash: NodeHash) -> Result<Option<LeafNode>, Self::Error> {
+        (**self).fetch_leaf(namespace, hash)
+    }
+    fn insert_compacted_leaf(
+        &self,
+        namespace: &str,
+        leaf: CompactedLeafNode,
+    ) -> Result<(), Self::Error> {
+        (**self).insert_compacted_leaf(namespace, leaf)
+    }
+    fn delete_compacted_leaf(&self, namespace: &str, hash: NodeHash) -> Result<(), Self::Error> {
+        (**self).delete_compacted_leaf(namespace, hash)
+    }
+    fn fetch_compacted_leaf(
+        &self,
+        namespace: &str,
+        hash: NodeHash,
+    ) -> Result<Option<CompactedLeafNode>, Self::Error> {
+        (**self).fetch_compacted_leaf(namespace, hash)
+    }
+}
+
+/// Checks that a backend behaves like a [TreeStore] should. Every backend should pass this,
+/// so they are interchangeable.
+#[cfg(test)]
+pub(crate) mod test {
+    use std::fmt::Debug;
+
+    use super::{Op, TreeStore};
+    use crate::mssmt::{
+        node::{CompactedLeafNode, DiskBranchNode, LeafNode, MSSMTNode},
+        node_hash::NodeHash,
+        proof::{Provable, Verifiable},
+        tree::{MSSMTree, Tree},
+    };
+
+    const NS: &str = "test";
+
+    pub(crate) fn test_store<Store: TreeStore>(store: Store)
+    where
+        Store::Error: Debug,
+    {
+        let leaf = LeafNode::new(vec![1, 2, 3], 10);
+        store.insert_leaf(NS, leaf.clone()).unwrap();
+        // Inserting the same node twice is fine
+        store.insert_leaf(NS, leaf.clone()).unwrap();
+        let found = store.fetch_leaf(NS, leaf.node_hash()).unwrap().unwrap();
+        assert_eq!(found, leaf);
+
+        let branch = DiskBranchNode::new(u64::MAX, [1; 32].into(), [2; 32].into());
+        store.insert_branch(NS, branch.clone()).unwrap();
+        let found = store.fetch_branch(NS, branch.node_hash()).unwrap().unwrap();
+        assert_eq!(found.node_hash(), branch.node_hash());
+        assert_eq!(found.node_sum(), u64::MAX);
+        assert_eq!(found.l_child(), &NodeHash::from([1; 32]));
+
+        let compacted = CompactedLeafNode::new(10, [3; 32].into(), leaf.clone());
+        store.insert_compacted_leaf(NS, compacted.clone()).unwrap();
+        let found = store
+            .fetch_compacted_leaf(NS, compacted.node_hash())
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.node_hash(), compacted.node_hash());
+        assert_eq!(found.key(), compacted.key());
+        assert_eq!(found.height(), 10);
+
+        // Each kind of node is only returned by it's own fetch
+        assert!(store.fetch_branch(NS, leaf.node_hash()).unwrap().is_none());
+        assert!(store.fetch_leaf(NS, branch.node_hash()).unwrap().is_none());
+
+        store.delete_leaf(NS, leaf.node_hash()).unwrap();
+        store.delete_branch(NS, branch.node_hash()).unwrap();
+        store
+            .delete_compacted_leaf(NS, compacted.node_hash())
+            .unwrap();
+        assert!(store.fetch_leaf(NS, leaf.node_hash()).unwrap().is_none());
+        assert!(store
+            .fetch_branch(NS, branch.node_hash())
+            .unwrap()
+            .is_none());
+        assert!(store
+            .fetch_compacted_leaf(NS, compacted.node_hash())
+            .unwrap()
+            .is_none());
+        // Deleting something that isn't there does nothing
+        store.delete_leaf(NS, leaf.node_hash()).unwrap();
+
+        // Batches are applied in order
+        store
+            .apply_batch(
+                NS,
+                vec![
+                    Op::InsertLeaf(leaf.clone()),
+                    Op::InsertBranch(branch.clone()),
+                    Op::DeleteLeaf(leaf.node_hash()),
+                    Op::InsertCompactedLeaf(compacted.clone()),
+                ],
+            )
+            .unwrap();
+        assert!(store.fetch_leaf(NS, leaf.node_hash()).unwrap().is_none());
+        assert!(store
+            .fetch_branch(NS, branch.node_hash())
+            .unwrap()
+            .is_some());
+        store
+            .apply_batch(
+                NS,
+                vec![
+                    Op::DeleteBranch(branch.node_hash()),
+                    Op::DeleteCompactedLeaf(compacted.node_hash()),
+                ],
+            )
+            .unwrap();
+        assert!(store
+            .fetch_branch(NS, branch.node_hash())
+            .unwrap()
+            .is_none());
+        assert!(store
+            .fetch_compacted_leaf(NS, compacted.node_hash())
+            .unwrap()
+            .is_none());
+
+        // Namespaces don't share nodes
+        store.insert_leaf(NS, leaf.clone()).unwrap();
+        store.insert_leaf("other", leaf.clone()).unwrap();
+        assert!(store
+            .fetch_leaf("empty", leaf.node_hash())
+            .unwrap()
+            .is_none());
+        store.delete_leaf("other", leaf.node_hash()).unwrap();
+        assert!(store.fetch_leaf(NS, leaf.node_hash()).unwrap().is_some());
+        assert!(store
+            .fetch_leaf("other", leaf.node_hash())
+            .unwrap()
+            .is_none());
+        store.delete_leaf(NS, leaf.node_hash()).unwrap();
+
+        // Each namespace has it's own root
+        assert!(store.get_root("tree").unwrap().is_none());
+        store.set_root("tree", [1; 32].into()).unwrap();
+        store
+            .apply_batch("other", vec![Op::SetRoot([2; 32].into())])
+            .unwrap();
+        assert_eq!(store.get_root("tree").unwrap(), Some([1; 32].into()));
+        assert_eq!(store.get_root("other").unwrap(), Some([2; 32].into()));
+
+        // Reference counts too, and zero removes them
+        let hash = leaf.node_hash();
+        assert_eq!(store.get_ref_count(NS, &hash).unwrap(), 0);
+        store
+            .apply_batch(NS, vec![Op::SetRefCount(hash, 2)])
+            .unwrap();
+        assert_eq!(store.get_ref_count(NS, &hash).unwrap(), 2);
+        assert_eq!(store.get_ref_count("other", &hash).unwrap(), 0);
+        store
+            .apply_batch(NS, vec![Op::SetRefCount(hash, 0)])
+            .unwrap();
+        assert_eq!(store.get_ref_count(NS, &hash).unwrap(), 0);
+
+        // A whole tree on top of this store
+        let mut tree = MSSMTree::open(&store, "tree").unwrap();
+        // Points to nodes we don't have
+        assert!(tree.check_consistency().is_err());
+        tree = MSSMTree::open(&store, "new tree").unwrap();
+        for i in 0..10_u8 {
+            tree.insert(NodeHash::from([i; 32]), vec![i], i as u64)
+                .unwrap();
+        }
+        tree.delete(NodeHash::from([0; 32])).unwrap();
+        let root = tree.root().unwrap();
+        assert_eq!(root.node_sum(), 45);
+        for i in 1..10_u8 {
+            let key = NodeHash::from([i; 32]);
+            let leaf = tree.lookup(key).unwrap().unwrap();
+            let proof = tree.prove(key).unwrap();
+            assert!(proof.verify_root(&leaf, &key, &root).unwrap());
+        }
+        let subtree = store
+            .fetch_branch_recursive("new tree", root.node_hash(), 5)
+            .unwrap()
+            .unwrap();
+        assert_eq!(subtree.node_hash(), root.node_hash());
+
+        let tree = MSSMTree::open(&store, "new tree").unwrap();
+        assert_eq!(tree.root_hash(), root.node_hash());
+    }
 }
@@ -13,7 +13,7 @@ impl Key {
         // which u8 should we take? We simple look at integer division of i by 8
         let limb = i / 8;
         let mask = 1 << (i % 8);
-        return (self.0[limb as usize] & mask) > 0;
+        (self.0[limb as usize] & mask) > 0
     }
 }
 
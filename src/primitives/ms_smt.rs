@@ -43,7 +43,7 @@
 //!  let mut tree = Tree::new();
 //!
 //!  tree.insert(key.clone(), value.into(), sum).unwrap();
-//!  tree.delete(key);
+//!  tree.delete(key).unwrap();
 //!  let root_hash = &tree.root_hash();
 //!  assert_eq!(
 //!          root_hash.to_string().as_str(),
@@ -52,6 +52,7 @@
 //! ```
 
 use super::{key::Key, node_hash::NodeHash};
+use crate::mssmt::error::SumOverflow;
 use sha2::Digest;
 
 #[derive(Debug, Default, PartialEq, Clone)]
@@ -80,13 +81,13 @@ impl TreeNode {
         !key.bit_index(idx)
     }
     #[inline(always)]
-    pub fn insert(&mut self, new_node: TreeNode, key: Key, idx: u8) {
+    pub fn insert(&mut self, new_node: TreeNode, key: Key, idx: u8) -> Result<(), SumOverflow> {
         if new_node == *self {
-            return;
+            return Ok(());
         }
         if self.is_target(key) {
             *self = new_node;
-            return;
+            return Ok(());
         }
         let target = if Self::is_left(idx, key) {
             &mut self.left
@@ -94,9 +95,9 @@ impl TreeNode {
             &mut self.right
         };
         match target {
-            Some(ref mut node) => node.insert(new_node, key, idx + 1),
+            Some(ref mut node) => node.insert(new_node, key, idx + 1)?,
             None => {
-                let sum = new_node.sum + self.sum;
+                let sum = new_node.sum.checked_add(self.sum).ok_or(SumOverflow)?;
                 let this_node = std::mem::take(self);
                 let new_parent = if Self::is_left(idx, key) {
                     TreeNode {
@@ -118,22 +119,23 @@ impl TreeNode {
                 *self = new_parent;
             }
         }
-        self.recompute_hash();
+        self.recompute_hash()
     }
 
-    fn recompute_hash(&mut self) {
+    fn recompute_hash(&mut self) -> Result<(), SumOverflow> {
         let hash = sha2::Sha256::new()
             .chain_update(self.get_left_data())
             .chain_update(self.get_right_data())
             .finalize();
-        self.sum = self.get_sum();
+        self.sum = self.get_sum()?;
         self.data = (*hash).try_into().unwrap();
+        Ok(())
     }
-    fn get_sum(&self) -> u64 {
+    fn get_sum(&self) -> Result<u64, SumOverflow> {
         if let (Some(left), Some(right)) = (&self.left, &self.right) {
-            return left.sum + right.sum;
+            return left.sum.checked_add(right.sum).ok_or(SumOverflow);
         }
-        0
+        Ok(0)
     }
     fn get_left_data(&self) -> NodeHash {
         if let Some(ref left) = self.left {
@@ -154,7 +156,7 @@ impl TreeNode {
         }
     }
     #[inline(always)]
-    pub fn delete(&mut self, key: Key, idx: u8) {
+    pub fn delete(&mut self, key: Key, idx: u8) -> Result<(), SumOverflow> {
         let target = if Self::is_left(idx, key) {
             &mut self.left
         } else {
@@ -170,11 +172,11 @@ impl TreeNode {
                     *self = *self.left.clone().unwrap();
                 }
             } else {
-                target.delete(key, idx + 1);
+                target.delete(key, idx + 1)?;
             }
         }
 
-        self.recompute_hash();
+        self.recompute_hash()
     }
 
     pub fn prove(&self, proof: &mut Vec<(NodeHash, u64)>, key: Key, level: u8) {
@@ -194,6 +196,7 @@ impl TreeNode {
     }
 }
 
+#[derive(Default)]
 pub struct Tree {
     root: Option<TreeNode>,
     leaves: u64,
@@ -219,13 +222,13 @@ impl Tree {
         for idx in 0..=31 {
             if (key & (1 << idx)) != 0 {
                 if let Some(ref current_node) = node.right {
-                    node = &*current_node;
+                    node = current_node;
                 } else {
                     break;
                 }
             } else {
                 if let Some(ref current_node) = node.left {
-                    node = &*current_node;
+                    node = current_node;
                 } else {
                     break;
                 }
@@ -233,7 +236,7 @@ impl Tree {
         }
         Ok(node)
     }
-    pub fn insert(&mut self, key: Key, data: NodeHash, sum: u64) -> Result<(), &'static str> {
+    pub fn insert(&mut self, key: Key, data: NodeHash, sum: u64) -> Result<(), SumOverflow> {
         let new_node = TreeNode {
             left: None,
             right: None,
@@ -246,8 +249,7 @@ impl Tree {
             self.root = Some(new_node);
             return Ok(());
         }
-        self.root.as_mut().unwrap().insert(new_node, key, 0);
-        Ok(())
+        self.root.as_mut().unwrap().insert(new_node, key, 0)
     }
     pub fn prove(&self, key: Key) -> Vec<(NodeHash, u64)> {
         let mut proof = vec![];
@@ -257,22 +259,24 @@ impl Tree {
         self.root.as_ref().unwrap().prove(&mut proof, key, 0);
         proof
     }
-    pub fn delete(&mut self, key: Key) {
+    pub fn delete(&mut self, key: Key) -> Result<(), SumOverflow> {
         if self.root.is_none() {
-            return;
+            return Ok(());
         }
         if self.leaves == 1 {
             self.root = None;
-            return;
+            return Ok(());
         }
-        self.root.as_mut().unwrap().delete(key, 0);
+        self.root.as_mut().unwrap().delete(key, 0)?;
         self.leaves -= 1;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::Tree;
+    use crate::mssmt::error::SumOverflow;
 
     #[test]
     fn test_insertion() {
@@ -320,4 +324,10 @@ mod test {
             "0505050505050505050505050505050505050505050505050505050505050505",
         );
     }
+    #[test]
+    fn test_sum_overflow() {
+        let mut tree = Tree::new();
+        tree.insert(0.into(), [0; 32].into(), u64::MAX).unwrap();
+        assert_eq!(tree.insert(1.into(), [1; 32].into(), 1), Err(SumOverflow));
+    }
 }
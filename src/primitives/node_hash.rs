@@ -3,7 +3,7 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Default)]
 pub struct NodeHash([u8; 32]);
 
 impl Debug for NodeHash {
@@ -22,11 +22,6 @@ impl DerefMut for NodeHash {
         &mut self.0
     }
 }
-impl Default for NodeHash {
-    fn default() -> Self {
-        NodeHash([0; 32])
-    }
-}
 impl From<[u8; 32]> for NodeHash {
     fn from(value: [u8; 32]) -> Self {
         NodeHash(value)
@@ -108,5 +103,4 @@ mod test {
         let parsed = NodeHash::try_from(hash);
         assert!(parsed.is_err());
     }
-
 }